@@ -1,8 +1,8 @@
 use std::io;
-use std::io::{Read, Stdin, Stdout, Write};
+use std::io::{Stdin, Stdout, Write};
 use std::net::{Shutdown, TcpStream, ToSocketAddrs};
-use crate::error::{Result, FSError};
-use crate::command::{parse_from_string};
+use crate::error::Result;
+use crate::command::{parse_from_string, Response};
 pub struct Shell{
     connection: TcpStream,
     stdin: Stdin,
@@ -11,8 +11,6 @@ pub struct Shell{
 
 impl Shell {
     pub fn connect<A: ToSocketAddrs>(address: A) -> Result<Self> {
-        let mut stdout = io::stdout();
-        let mut stdin = io::stdin();
         Ok(Self{
             connection: TcpStream::connect(address)?,
             stdout: io::stdout(),
@@ -34,11 +32,13 @@ impl Shell {
                             let bytes = bincode::serialize(&cmd)?;
 
                             self.connection.write_all(&bytes)?;
-                            let mut res = vec![];
-                            self.connection.read_exact(&mut res)?;
-                            self.stdout.write_all(&res)?;
+                            let res: Response = bincode::deserialize_from(&self.connection)?;
+                            match res {
+                                Ok(output) => {self.stdout.write_all(&output)?;},
+                                Err(message) => {writeln!(io::stderr(), "{message}")?;},
+                            }
                         }
-                        Err(e) => {self.stdout.write_all(b"Command error, use \"--help\" to see help.")?;}
+                        Err(_) => {self.stdout.write_all(b"Command error, use \"--help\" to see help.")?;}
                     }
                 }
             }
@@ -54,9 +54,3 @@ impl Shell {
 
 
 }
-
-fn main() -> Result<()>{
-    let shell = Shell::connect("127.0.0.1:6000")?;
-    shell.run()?;
-    Ok(())
-}
\ No newline at end of file
@@ -1,5 +1,6 @@
 use serde::{Serialize, Deserialize};
 use crate::error::{Result, FSError};
+#[allow(non_camel_case_types)]
 #[derive(Serialize, Deserialize)]
 pub enum CommandType {
     mkdir,
@@ -11,6 +12,13 @@ pub enum CommandType {
     append,
     cat,
     rm,
+    mv,
+    rm_r,
+    stat,
+    ls_l,
+    ln,
+    ln_s,
+    readlink,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -19,9 +27,12 @@ pub struct Command {
     pub parameter: Vec<u8>,
 }
 
+/// Answer to a command: its output, or the message telling why it failed.
+pub type Response = std::result::Result<Vec<u8>, String>;
+
 pub fn parse_from_string(buf: String) -> Result<Command> {
-    let mut cmd;
-    let mut parameter;
+    let cmd;
+    let parameter;
     let mut bytes = buf.into_bytes();
     if bytes.starts_with(b"mkdir ") {
         cmd = CommandType::mkdir;
@@ -30,6 +41,12 @@ pub fn parse_from_string(buf: String) -> Result<Command> {
         return Ok(Command{ cmd,parameter});
 
     }
+    else if bytes.starts_with(b"ls -l") {
+        cmd = CommandType::ls_l;
+        bytes.drain(0.."ls -l ".len().min(bytes.len()));
+        parameter = bytes;
+        return Ok(Command{ cmd,parameter});
+    }
     else if bytes.starts_with(b"ls") {
         cmd = CommandType::ls;
         bytes.drain(0.."ls ".len());
@@ -60,12 +77,122 @@ pub fn parse_from_string(buf: String) -> Result<Command> {
         parameter = bytes;
         return Ok(Command{ cmd,parameter});
     }
+    else if bytes.starts_with(b"rm -r ") {
+        cmd = CommandType::rm_r;
+        bytes.drain(0.."rm -r ".len());
+        parameter = bytes;
+        return Ok(Command{ cmd,parameter});
+    }
     else if bytes.starts_with(b"rm ") {
         cmd = CommandType::rm;
         bytes.drain(0.."rm ".len());
         parameter = bytes;
         return Ok(Command{ cmd,parameter});
     }
+    else if bytes.starts_with(b"stat ") {
+        cmd = CommandType::stat;
+        bytes.drain(0.."stat ".len());
+        parameter = bytes;
+        return Ok(Command{ cmd,parameter});
+    }
+    else if bytes.starts_with(b"cat ") {
+        cmd = CommandType::cat;
+        bytes.drain(0.."cat ".len());
+        parameter = bytes;
+        return Ok(Command{ cmd,parameter});
+    }
+    else if bytes.starts_with(b"append ") {
+        cmd = CommandType::append;
+        bytes.drain(0.."append ".len());
+        // The rest of the line after the path is appended as it is, in the
+        // same parameter as the path and split from it by a NUL.
+        let data_start = bytes.iter().position(|&b| b == b' ').ok_or(FSError::CmdParseError)?;
+        bytes[data_start] = 0;
+        if bytes.ends_with(b"\n") {
+            bytes.pop();
+            if bytes.ends_with(b"\r") {
+                bytes.pop();
+            }
+        }
+        parameter = bytes;
+        return Ok(Command{ cmd,parameter});
+    }
+    else if bytes.starts_with(b"readlink ") {
+        cmd = CommandType::readlink;
+        bytes.drain(0.."readlink ".len());
+        parameter = bytes;
+        return Ok(Command{ cmd,parameter});
+    }
+    else if bytes.starts_with(b"mv ") || bytes.starts_with(b"ln ") {
+        let prefix_len;
+        (cmd, prefix_len) = match (bytes.starts_with(b"mv "), bytes.starts_with(b"ln -s ")) {
+            (true, _) => (CommandType::mv, "mv ".len()),
+            (false, true) => (CommandType::ln_s, "ln -s ".len()),
+            (false, false) => (CommandType::ln, "ln ".len()),
+        };
+        bytes.drain(0..prefix_len);
+        // Both paths travel in one parameter, split by a NUL no path can contain.
+        let args = String::from_utf8(bytes).map_err(|_| FSError::CmdParseError)?;
+        match split_args(&args)?.as_slice() {
+            [src, dst] => {
+                parameter = [src.as_bytes(), b"\0", dst.as_bytes()].concat();
+                return Ok(Command{ cmd,parameter});
+            },
+            _ => {return Err(FSError::CmdParseError);}
+        }
+    }
 
     Err(FSError::CmdParseError)
-}
\ No newline at end of file
+}
+
+/// Splits `args` into words at whitespace, the way a shell does. A word can hold
+/// whitespace inside single or double quotes, or escaped with a backslash, which
+/// also escapes a quote or another backslash except inside single quotes.
+fn split_args(args: &str) -> Result<Vec<String>> {
+    let mut words = Vec::new();
+    let mut word: Option<String> = None;
+    let mut quote = None;
+    let mut chars = args.chars();
+    while let Some(c) = chars.next() {
+        match (c, quote) {
+            ('\\', Some('\'')) => word.get_or_insert_with(String::new).push(c),
+            ('\\', _) => {
+                let escaped = chars.next().ok_or(FSError::CmdParseError)?;
+                word.get_or_insert_with(String::new).push(escaped);
+            },
+            (c, Some(q)) if c == q => quote = None,
+            (c, Some(_)) => word.get_or_insert_with(String::new).push(c),
+            ('"' | '\'', None) => {
+                quote = Some(c);
+                word.get_or_insert_with(String::new);
+            },
+            (c, None) if c.is_whitespace() => words.extend(word.take()),
+            (c, None) => word.get_or_insert_with(String::new).push(c),
+        }
+    }
+    if quote.is_some() {
+        return Err(FSError::CmdParseError);
+    }
+    words.extend(word);
+    Ok(words)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_quoted_paths() -> Result<()> {
+        let cmd = parse_from_string("mv \"my notes\" 'old notes'\n".to_owned())?;
+        assert!(matches!(cmd.cmd, CommandType::mv));
+        assert_eq!(cmd.parameter, b"my notes\0old notes");
+        let cmd = parse_from_string("ln -s a\\ b/c d\\\"e\n".to_owned())?;
+        assert!(matches!(cmd.cmd, CommandType::ln_s));
+        assert_eq!(cmd.parameter, b"a b/c\0d\"e");
+        let cmd = parse_from_string("ln '' x\n".to_owned())?;
+        assert_eq!(cmd.parameter, b"\0x");
+        assert!(parse_from_string("mv \"unterminated dst\n".to_owned()).is_err());
+        assert!(parse_from_string("mv one two three\n".to_owned()).is_err());
+        Ok(())
+    }
+}
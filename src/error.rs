@@ -1,4 +1,3 @@
-use std::net::TcpStream;
 use thiserror::Error;
 #[derive(Error, Debug)]
 pub enum FSError {
@@ -47,6 +46,38 @@ pub enum FSError {
     #[error("508 Append exceeds maximum file size")]
     FileFullError,
 
+    #[error("509 Disk is not formatted")]
+    NotFormattedError,
+
+    #[error("510 Unsupported disk format: {0}")]
+    UnsupportedFormatError(String),
+
+    #[error("511 Volume label is too long")]
+    LabelTooLongError,
+
+    #[error("512 Invalid volume geometry: {0}")]
+    InvalidGeometryError(String),
+
+    #[error("513 Invalid file name")]
+    InvalidFileNameError,
+
+    #[error("514 Cannot move a directory into itself")]
+    InvalidMoveError,
+
+    #[error("515 Removal stopped after {removed} entries: {source}")]
+    PartialRemoveError {
+        removed: usize,
+        source: Box<FSError>,
+    },
+
+    #[error("516 Too many links")]
+    TooManyLinksError,
+
+    #[error("517 Too many levels of symbolic links")]
+    SymlinkLoopError,
+
+    #[error("518 File is not a symbolic link")]
+    NotSymlinkError,
 }
 
 pub type Result<T> = std::result::Result<T, FSError>;
@@ -0,0 +1,5 @@
+pub mod server;
+pub mod error;
+pub mod virtual_file_sys;
+pub mod client;
+pub mod command;
@@ -1,38 +1,46 @@
+use std::env;
+use sarari::virtual_file_sys::{file_sys::FileSys, FormatOptions};
+use sarari::server::NFServer;
+use sarari::client::Shell;
+use sarari::error::{FSError, Result};
 
+const DEFAULT_ADDRESS: &str = "127.0.0.1:6000";
 
-mod server;
-mod error;
-mod virtual_file_sys;
-mod client;
-mod command;
+fn usage() {
+    eprintln!("usage: sarari mkfs <image> [num_blocks] [block_size] [label] [num_inodes]");
+    eprintln!("       sarari server <image> [address]");
+    eprintln!("       sarari shell [address]");
+}
 
-use virtual_file_sys::file_sys::FileSys;
-use error::{Result};
-fn main() ->Result<()>{
+fn parse_arg(arg: Option<&String>, default: usize) -> Result<usize> {
+    match arg {
+        Some(arg) => arg.parse().map_err(|_| FSError::CmdParseError),
+        None => Ok(default),
+    }
+}
 
-    let mut fs = FileSys::mount()?;
-    fs.create(b"file00001".to_owned())?;
-    fs.create(b"file00002".to_owned())?;
-    fs.create(b"file00003".to_owned())?;
-    fs.create(b"file00004".to_owned())?;
-    fs.mkdir(b"dir000001".to_owned())?;
-    fs.mkdir(b"dir000002".to_owned())?;
-    fs.mkdir(b"dir000003".to_owned())?;
-    let res = fs.ls()?;
-    println!("{res}");
-    fs.append(b"file00001".to_owned(), &vec![b'h',b'e',b'l',b'l',b'o',b'\n',b'w',b'o',b'r',b'l',b'd'])?;
-    let res = fs.cat(b"file00001".to_owned())?;
-    println!("{res}");
-    fs.cd(b"dir000001".to_owned())?;
-    fs.create(b"file00011".to_owned())?;
-    fs.create(b"file00022".to_owned())?;
-    fs.create(b"file00033".to_owned())?;
-    fs.create(b"file00044".to_owned())?;
-    fs.mkdir(b"dir000011".to_owned())?;
-    fs.mkdir(b"dir000022".to_owned())?;
-    fs.mkdir(b"dir000033".to_owned())?;
-    let res = fs.ls()?;
-    
-    print!("{res}");
+fn main() ->Result<()>{
+    let args: Vec<String> = env::args().collect();
+    match (args.get(1).map(String::as_str), args.get(2)) {
+        (Some("mkfs"), Some(image)) => {
+            let defaults = FormatOptions::default();
+            let options = FormatOptions {
+                num_blocks: parse_arg(args.get(3), defaults.num_blocks)?,
+                block_size: parse_arg(args.get(4), defaults.block_size)?,
+                label: args.get(5).cloned().unwrap_or_default(),
+                num_inodes: args.get(6).map(|arg| parse_arg(Some(arg), 0)).transpose()?,
+                ..defaults
+            };
+            FileSys::format(image, &options)?.unmount()?;
+        },
+        (Some("server"), Some(image)) => {
+            let address = args.get(3).map(String::as_str).unwrap_or(DEFAULT_ADDRESS);
+            NFServer::bind(address, image)?.run()?;
+        },
+        (Some("shell"), address) => {
+            Shell::connect(address.map(String::as_str).unwrap_or(DEFAULT_ADDRESS))?.run()?;
+        },
+        _ => usage(),
+    }
     Ok(())
 }
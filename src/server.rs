@@ -1,9 +1,8 @@
 use std::cell::RefCell;
-use std::io::{Read, Write};
 use std::net::{TcpListener, TcpStream, ToSocketAddrs};
-use std::thread::spawn;
-use crate::command::{Command, CommandType};
-use crate::virtual_file_sys::{file_sys::FileSys, MAX_FNAME_SIZE};
+use std::path::Path;
+use crate::command::{Command, CommandType, Response};
+use crate::virtual_file_sys::file_sys::FileSys;
 use crate::error::{FSError, Result};
 
 pub struct NFServer {
@@ -12,78 +11,140 @@ pub struct NFServer {
 }
 
 impl NFServer {
-    pub fn bind<A: ToSocketAddrs>(address: A) -> Result<Self>{
+    pub fn bind<A: ToSocketAddrs, P: AsRef<Path>>(address: A, image: P) -> Result<Self>{
         Ok(Self{
-            fs: RefCell::new(FileSys::mount()?),
+            fs: RefCell::new(FileSys::mount(image)?),
             connection: TcpListener::bind(address)?
         })
     }
 
     pub fn run(&self) -> Result<()>{
-        for stream in self.connection.incoming() {
-            match stream {
-                Ok(stream) => {self.handle_stream(stream)?;}
-                Err(_) => {}
-            }
+        for stream in self.connection.incoming().flatten() {
+            self.handle_stream(stream)?;
         }
         Ok(())
     }
 
-    pub fn handle_stream(&self, mut stream: TcpStream) -> Result<()> {
+    /// Serves the commands of one client. Every command gets one response, sent
+    /// as a length-prefixed byte string so file contents come through unchanged,
+    /// or as an error message when the command failed.
+    pub fn handle_stream(&self, stream: TcpStream) -> Result<()> {
         while let Ok(cmd) = bincode::deserialize_from::<&TcpStream, Command>(&stream) {
-            let mut response = String::new();
-            match cmd.cmd {
-                CommandType::create => {
-                    if cmd.parameter.len() > MAX_FNAME_SIZE || cmd.parameter.len() == 0 {
-                        response = "Invalid file name size.".to_owned();
-                    }
-                    else {
-                        let mut name = [0u8; MAX_FNAME_SIZE];
-                        for (i, ch) in cmd.parameter.iter().enumerate() {name[i] = ch.to_owned()};
-                        match self.fs.borrow().create(name) {
-                            Ok(res) => {},
-                            Err(FSError::DirFullError) => {response = "Directory is full.".to_owned()},
-                            Err(FSError::FileExistsError) => {response = "File is allour already exist.".to_owned()},
-                            _ => unreachable!()
-                        }
-                    }
-                },
-                CommandType::ls => {
-                    match self.fs.borrow().ls() {
-                        Ok(res) => {response = res;},
-                        Err(_) => {response = "File system error.".to_owned();}
-                    }
-                },
-                CommandType::cd => {
-                    if cmd.parameter.len() > MAX_FNAME_SIZE || cmd.parameter.len() == 0 {
-                        response = "Invalid file name size.".to_owned();
-                    }
-                    else {
-                        let mut name = [0u8; MAX_FNAME_SIZE];
-                        for (i, ch) in cmd.parameter.iter().enumerate() {name[i] = ch.to_owned()};
-                        match self.fs.borrow_mut().cd(name) {
-                            Ok(_) => {},
-                            Err(FSError::FileNotFoundError) => {response = "Directory not find.".to_owned();},
-                            Err(_) => {response = "File system error.".to_owned();}
-                        }
-                    }
-                },
-                CommandType::home => {},
-                CommandType::append => {},
-                CommandType::cat => {},
-                CommandType::rm => {},
-                _ =>{response = "Error command.".to_owned();}
-            }
-            stream.write_all(response.as_bytes())?;
+            let response: Response = self.execute(&cmd).map_err(|e| error_message(&e));
+            bincode::serialize_into(&stream, &response)?;
         }
         Ok(())
     }
 
+    /// Runs one command against the file system and returns its output.
+    fn execute(&self, cmd: &Command) -> Result<Vec<u8>> {
+        match cmd.cmd {
+            CommandType::mkdir => {
+                self.fs.borrow().mkdir(path_arg(&cmd.parameter)?)?;
+            },
+            CommandType::rmdir => {
+                self.fs.borrow_mut().rmdir(path_arg(&cmd.parameter)?)?;
+            },
+            CommandType::create => {
+                self.fs.borrow().create(path_arg(&cmd.parameter)?)?;
+            },
+            CommandType::ls => {
+                let path = parse_path(&cmd.parameter).unwrap_or(".");
+                return Ok(self.fs.borrow().ls(path)?.into_bytes());
+            },
+            CommandType::ls_l => {
+                let path = parse_path(&cmd.parameter).unwrap_or(".");
+                return Ok(self.fs.borrow().ls_long(path)?.into_bytes());
+            },
+            CommandType::stat => {
+                let stat = self.fs.borrow().stat(path_arg(&cmd.parameter)?)?;
+                return Ok(stat.to_string().into_bytes());
+            },
+            CommandType::cd => {
+                self.fs.borrow_mut().cd(path_arg(&cmd.parameter)?)?;
+            },
+            CommandType::mv => {
+                let (src, dst) = path_args(&cmd.parameter)?;
+                self.fs.borrow_mut().rename(src, dst)?;
+            },
+            CommandType::ln => {
+                let (existing, new_path) = path_args(&cmd.parameter)?;
+                self.fs.borrow().link(existing, new_path)?;
+            },
+            CommandType::ln_s => {
+                let (target, link_path) = path_args(&cmd.parameter)?;
+                self.fs.borrow().symlink(target, link_path)?;
+            },
+            CommandType::readlink => {
+                return Ok(self.fs.borrow().readlink(path_arg(&cmd.parameter)?)?.into_bytes());
+            },
+            CommandType::home => {
+                self.fs.borrow_mut().home()?;
+            },
+            CommandType::append => {
+                // The path and the data to append are split by a NUL no path can contain.
+                let mut parts = cmd.parameter.splitn(2, |&b| b == 0);
+                match (parts.next().and_then(parse_path), parts.next()) {
+                    (Some(path), Some(data)) => self.fs.borrow().append(path, data)?,
+                    _ => return Err(FSError::InvalidFileNameError),
+                }
+            },
+            CommandType::cat => {
+                return self.fs.borrow().cat(path_arg(&cmd.parameter)?);
+            },
+            CommandType::rm => {
+                self.fs.borrow_mut().rm(path_arg(&cmd.parameter)?)?;
+            },
+            CommandType::rm_r => {
+                self.fs.borrow_mut().rm_r(path_arg(&cmd.parameter)?)?;
+            },
+        }
+        Ok(Vec::new())
+    }
+
+}
+
+/// Message sent back to the client for a command that failed.
+fn error_message(err: &FSError) -> String {
+    match err {
+        FSError::FileNotFoundError => "File not find.".into(),
+        FSError::NotDirError => "Not a directory.".into(),
+        FSError::NotAFileError => "Is a directory.".into(),
+        FSError::FileExistsError => "File is allour already exist.".into(),
+        FSError::FileNameTooLongError => "File name is too long.".into(),
+        FSError::InvalidFileNameError => "Invalid file name.".into(),
+        FSError::DiskFullError => "Disk is full.".into(),
+        FSError::DirFullError => "Directory is full.".into(),
+        FSError::DirNotEmptyError => "Directory is not empty.".into(),
+        FSError::FileFullError => "File is full.".into(),
+        FSError::InvalidMoveError => "Cannot move a directory into itself.".into(),
+        FSError::TooManyLinksError => "Too many links.".into(),
+        FSError::SymlinkLoopError => "Too many levels of symbolic links.".into(),
+        FSError::NotSymlinkError => "Not a symbolic link.".into(),
+        FSError::PartialRemoveError { removed, .. } => format!("File system error after removing {removed} entries."),
+        _ => "File system error.".into(),
+    }
 }
 
-fn main() ->Result<()>{
-    let nfs = NFServer::bind("127.0.0.1:6000")?;
-    nfs.run();
+/// Reads the single path a command takes.
+fn path_arg(parameter: &[u8]) -> Result<&str> {
+    parse_path(parameter).ok_or(FSError::InvalidFileNameError)
+}
+
+/// Reads the two NUL-separated paths of commands such as mv and ln.
+fn path_args(parameter: &[u8]) -> Result<(&str, &str)> {
+    let mut paths = parameter.splitn(2, |&b| b == 0).map(parse_path);
+    match (paths.next().flatten(), paths.next().flatten()) {
+        (Some(first), Some(second)) => Ok((first, second)),
+        _ => Err(FSError::InvalidFileNameError),
+    }
+}
 
-    Ok(())
-}
\ No newline at end of file
+/// Reads a path sent by a client, dropping the line ending the shell leaves on it.
+fn parse_path(parameter: &[u8]) -> Option<&str> {
+    let path = std::str::from_utf8(parameter).ok()?.trim_end_matches(['\r', '\n']);
+    match path.is_empty() {
+        true => None,
+        false => Some(path),
+    }
+}
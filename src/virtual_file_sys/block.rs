@@ -1,70 +1,200 @@
-use std::cell::{RefCell, RefMut, Ref};
-use std::sync::Arc;
-use serde::{Serialize, Deserialize};
 use crate::error::{FSError, Result};
-use crate::virtual_file_sys::file_sys::WrappedFileSys;
-
-pub const BLOCK_SIZE: usize = 1024;
-pub const NUM_BLOCKS: usize = BLOCK_SIZE * 8;
-pub const MAX_FNAME_SIZE: usize = 9;
-pub const MAX_DIR_ENTRIES: usize = (BLOCK_SIZE - 8) / 32;
-pub const MAX_DATA_BLOCKS: usize = (BLOCK_SIZE - 8) / 4;
-pub const MAX_FILE_SIZE: usize	= MAX_DATA_BLOCKS * BLOCK_SIZE;
-
-pub const DIR_MAGIC_NUM: usize = 0xFFFFFFFF;
-pub const INODE_MAGIC_NUM: usize = 0xFFFFFFFE;
-
-#[derive(Serialize, Deserialize)]
-pub struct DataBlock {
-    id: usize,
-    raw: RefCell<Vec<u8>>
-}
-
-impl DataBlock {
-    pub fn new(wrapped_file_sys: &WrappedFileSys) -> Result<Self> {
-        match wrapped_file_sys.file_sys().get_free_block() {
-            Some(id) => {
-                return Ok(Self {
-                    id,
-                    raw: RefCell::new(vec![0u8; BLOCK_SIZE]),
-                });
-            },
-            None => { return Err(FSError::DiskFullError); }
-        }
-    }
 
-    pub fn retrieve(wrapped_file_sys: &WrappedFileSys, id: usize) -> Result<Self> {
-        let mut block_data = vec![0u8; BLOCK_SIZE];
-        wrapped_file_sys.file_sys().read_block(id, &mut block_data)?;
-        Ok(Self {
-            id,
-            raw: RefCell::new(block_data)
-        })
-    }
+pub const DEFAULT_BLOCK_SIZE: usize = 1024;
+pub const DEFAULT_NUM_BLOCKS: usize = DEFAULT_BLOCK_SIZE * 8;
+pub const MIN_BLOCK_SIZE: usize = 512;
+pub const MAX_BLOCK_SIZE: usize = 64 * 1024;
+pub const MAX_FNAME_SIZE: usize = 255;
 
-    pub fn destroy(self, wrapped_file_sys: &WrappedFileSys) {
-        wrapped_file_sys.file_sys().reclaim_block(self.id);
-    }
+/// Bytes taken by one inode in the inode table.
+pub const INODE_SIZE: usize = 256;
+/// Bytes taken by the header every inode starts with.
+pub const INODE_HEADER_SIZE: usize = 16;
+/// Offset of the area of an inode holding its inline extents.
+pub const INODE_BLOCK_OFFSET: usize = 64;
+/// Bytes taken by a directory record ahead of its name.
+pub const DIR_RECORD_HEADER_SIZE: usize = 8;
+
+/// Bytes taken by the directory record of a name `name_len` bytes long,
+/// records being kept 4-byte aligned.
+#[inline]
+pub fn dir_record_len(name_len: usize) -> usize {
+    (DIR_RECORD_HEADER_SIZE + name_len).next_multiple_of(4)
+}
+
+/// Hash of a directory entry name, ordering the entries of a hashed directory
+/// index. 32-bit FNV-1a, so the order is the same on every platform.
+pub fn name_hash(name: &str) -> u32 {
+    name.bytes().fold(0x811C_9DC5, |hash, b| (hash ^ b as u32).wrapping_mul(0x0100_0193))
+}
 
-    pub fn get_id(&self) -> usize {
-        self.id
+/// Bytes taken by the header of an index block, up to its first entry.
+pub const INDEX_HEADER_SIZE: usize = 12;
+/// Bytes taken by one entry of an index block.
+pub const INDEX_ENTRY_SIZE: usize = 8;
+
+/// Number of entries held by one index block.
+#[inline]
+pub fn index_entries_per_block(block_size: usize) -> usize {
+    (block_size - INDEX_HEADER_SIZE) / INDEX_ENTRY_SIZE
+}
+
+/// Checks that `name` can be used as the name of a directory entry.
+pub fn validate_name(name: &str) -> Result<()> {
+    if name.len() > MAX_FNAME_SIZE {
+        return Err(FSError::FileNameTooLongError);
+    }
+    if name.is_empty() || name == "." || name == ".." || name.contains(['/', '\0']) {
+        return Err(FSError::InvalidFileNameError);
     }
+    Ok(())
+}
+
+/// Bytes taken by one extent on disk.
+pub const EXTENT_SIZE: usize = 12;
+
+/// Number of extents that fit in an inode after its header.
+pub const INLINE_EXTENTS: usize = (INODE_SIZE - INODE_BLOCK_OFFSET) / EXTENT_SIZE;
+/// Largest file whose contents are kept in the inode instead of its extents.
+pub const INLINE_DATA_SIZE: usize = INODE_SIZE - INODE_BLOCK_OFFSET;
+
+/// The inode holds the file contents in place of its extents.
+pub const INODE_FLAG_INLINE_DATA: u16 = 0x0001;
+/// The first block of the directory is the root of a hashed index of its entries.
+pub const INODE_FLAG_INDEX: u16 = 0x0002;
 
-    pub fn get_raw(&self) -> RefMut<Vec<u8>> {
-        self.raw.borrow_mut()
+/// Permission bits an inode mode can hold: rwx for owner, group and others,
+/// plus setuid, setgid and sticky.
+pub const MODE_MASK: u16 = 0o7777;
+/// Mode of a new file.
+pub const DEFAULT_FILE_MODE: u16 = 0o644;
+/// Mode of a new directory.
+pub const DEFAULT_DIR_MODE: u16 = 0o755;
+/// Mode of a new symbolic link, whose permissions are never checked.
+pub const DEFAULT_SYMLINK_MODE: u16 = 0o777;
+
+/// Longest path a symbolic link can point at.
+pub const MAX_SYMLINK_SIZE: usize = 4095;
+/// Symbolic links followed while resolving one path before giving up on it as a loop.
+pub const MAX_SYMLINK_FOLLOWS: usize = 40;
+
+/// Number of extents held by one extent block.
+#[inline]
+pub fn extents_per_block(block_size: usize) -> usize {
+    block_size / EXTENT_SIZE
+}
+
+/// Number of block pointers held by one indirect block.
+#[inline]
+pub fn pointers_per_block(block_size: usize) -> usize {
+    block_size / 4
+}
+
+/// Number of extents a file can hold inline, in its indirect extent block and
+/// in the extent blocks hanging off its double-indirect block.
+#[inline]
+pub fn max_extents(block_size: usize) -> usize {
+    INLINE_EXTENTS
+        + extents_per_block(block_size)
+        + pointers_per_block(block_size) * extents_per_block(block_size)
+}
+
+/// First four bytes of every inode ("SRIN" in a hex dump).
+pub const INODE_MAGIC: u32 = 0x4E49_5253;
+
+/// Type tag stored in the header of every inode, right after the magic number.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InodeType {
+    File = 1,
+    Dir = 2,
+    /// Symbolic link, holding the path it points at the way a file holds its contents.
+    Symlink = 3,
+}
+
+impl InodeType {
+    pub fn from_tag(tag: u16) -> Option<Self> {
+        match tag {
+            1 => Some(Self::File),
+            2 => Some(Self::Dir),
+            3 => Some(Self::Symlink),
+            _ => None,
+        }
     }
 
-    pub fn get_data(&self) ->Ref<Vec<u8>> {
-        self.raw.borrow()
+    /// Type of the inode stored in `inode`, or `None` if the slot holds no inode.
+    pub fn of_inode(inode: &[u8]) -> Option<Self> {
+        if inode.len() < INODE_HEADER_SIZE || get_u32(inode, 0) != INODE_MAGIC {
+            return None;
+        }
+        Self::from_tag(get_u16(inode, 4))
     }
+}
+
+/// Inode header, shared by every inode type:
+///
+/// | offset | size | field                                      |
+/// |--------|------|--------------------------------------------|
+/// | 0      | 4    | magic, `INODE_MAGIC`                       |
+/// | 4      | 2    | type tag, `InodeType`                      |
+/// | 6      | 2    | flags, `INODE_FLAG_*`                      |
+/// | 8      | 8    | size in bytes                              |
+pub fn put_inode_header(data: &mut [u8], inode_type: InodeType, flags: u16, size: u64) {
+    put_u32(data, 0, INODE_MAGIC);
+    put_u16(data, 4, inode_type as u16);
+    put_u16(data, 6, flags);
+    put_u64(data, 8, size);
+}
 
-    pub fn write_and_set_raw_block(&self, wrapped_file_sys: &WrappedFileSys, tmp_raw: Vec<u8>) -> Result<()> {
-        wrapped_file_sys.file_sys().write_block(self.id, &tmp_raw)?;
-        self.raw.replace(tmp_raw);
-        Ok(())
+/// Reads the type, flags and size fields of an inode header.
+pub fn get_inode_header(data: &[u8]) -> Result<(InodeType, u16, u64)> {
+    match InodeType::of_inode(data) {
+        Some(inode_type) => Ok((inode_type, get_u16(data, 6), get_u64(data, 8))),
+        None => Err(FSError::FileSysError),
     }
 }
 
+#[inline]
+pub fn get_u16(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([data[offset], data[offset + 1]])
+}
+
+#[inline]
+pub fn get_u32(data: &[u8], offset: usize) -> u32 {
+    let mut bytes = [0u8; 4];
+    bytes.copy_from_slice(&data[offset..offset + 4]);
+    u32::from_le_bytes(bytes)
+}
+
+#[inline]
+pub fn get_u64(data: &[u8], offset: usize) -> u64 {
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(&data[offset..offset + 8]);
+    u64::from_le_bytes(bytes)
+}
+
+#[inline]
+pub fn put_u16(data: &mut [u8], offset: usize, value: u16) {
+    data[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+}
+
+#[inline]
+pub fn put_u32(data: &mut [u8], offset: usize, value: u32) {
+    data[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+#[inline]
+pub fn put_u64(data: &mut [u8], offset: usize, value: u64) {
+    data[offset..offset + 8].copy_from_slice(&value.to_le_bytes());
+}
+
+/// Fails with a block error unless `block_data` holds at least `len` bytes.
+#[inline]
+pub fn check_len(block_data: &[u8], len: usize) -> Result<()> {
+    if block_data.len() < len {
+        return Err(FSError::BlockError("Block too short".to_owned()));
+    }
+    Ok(())
+}
 
 pub trait BlockBinary {
     fn to_bytes(&self) -> Result<Vec<u8>>;
@@ -72,10 +202,76 @@ pub trait BlockBinary {
     fn from_bytes(block_data: &[u8]) -> Result<Self> where Self: Sized;
 }
 
+/// Indirect block - a block filled with little-endian block pointers
+#[derive(Debug, Default)]
+pub struct PointerBlock {
+    pub pointers: Vec<u32>,
+}
 
+impl BlockBinary for PointerBlock {
+    fn to_bytes(&self) -> Result<Vec<u8>> {
+        Ok(self.pointers.iter().flat_map(|p| p.to_le_bytes()).collect())
+    }
 
+    fn from_bytes(block_data: &[u8]) -> Result<Self> {
+        let pointers = block_data.chunks_exact(4)
+            .map(|p| get_u32(p, 0))
+            .collect();
+        Ok(Self { pointers })
+    }
+}
 
+/// Extent - a run of `len` contiguous blocks starting at block `start`,
+/// holding the file blocks from `logical` onwards. An extent of length 0 is unused.
+///
+/// Stored as three little-endian u32s: `logical`, `start`, `len`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Extent {
+    pub logical: u32,
+    pub start: u32,
+    pub len: u32,
+}
 
+impl Extent {
+    #[inline]
+    pub fn logical_end(&self) -> usize {
+        self.logical as usize + self.len as usize
+    }
 
+    pub fn read(data: &[u8], offset: usize) -> Self {
+        Self {
+            logical: get_u32(data, offset),
+            start: get_u32(data, offset + 4),
+            len: get_u32(data, offset + 8),
+        }
+    }
 
+    pub fn write(&self, data: &mut [u8], offset: usize) {
+        put_u32(data, offset, self.logical);
+        put_u32(data, offset + 4, self.start);
+        put_u32(data, offset + 8, self.len);
+    }
+}
 
+/// Extent block - a block filled with little-endian extents
+#[derive(Debug, Default)]
+pub struct ExtentBlock {
+    pub extents: Vec<Extent>,
+}
+
+impl BlockBinary for ExtentBlock {
+    fn to_bytes(&self) -> Result<Vec<u8>> {
+        let mut bytes = vec![0u8; self.extents.len() * EXTENT_SIZE];
+        for (i, extent) in self.extents.iter().enumerate() {
+            extent.write(&mut bytes, i * EXTENT_SIZE);
+        }
+        Ok(bytes)
+    }
+
+    fn from_bytes(block_data: &[u8]) -> Result<Self> {
+        let extents = (0..block_data.len() / EXTENT_SIZE)
+            .map(|i| Extent::read(block_data, i * EXTENT_SIZE))
+            .collect();
+        Ok(Self { extents })
+    }
+}
@@ -2,36 +2,78 @@ use std::cell::RefCell;
 use std::path::Path;
 use std::fs::{File, OpenOptions};
 use std::io::{Read, Seek, SeekFrom, Write};
+use std::rc::Rc;
 use crate::error::{FSError, Result};
-use crate::virtual_file_sys::block::*;
-#[derive()]
+
+/// Storage addressed in fixed-size blocks
+pub trait BlockDevice {
+    fn read_block(&self, block_num: usize, block: &mut [u8]) -> Result<()>;
+
+    fn write_block(&self, block_num: usize, block: &[u8]) -> Result<()>;
+
+    /// Makes every completed write durable.
+    fn flush(&self) -> Result<()>;
+
+    fn block_count(&self) -> usize;
+
+    fn block_size(&self) -> usize;
+
+    fn check_block(&self, block_num: usize, len: usize) -> Result<()> {
+        if block_num >= self.block_count() {
+            return Err(FSError::BlockError("Invalid block size".to_owned()));
+        }
+        if len > self.block_size() {
+            return Err(FSError::BlockError("Buffer exceeds block size".to_owned()));
+        }
+        Ok(())
+    }
+}
+
+/// Disk - a block device backed by an image file
 pub struct Disk {
-    fd: RefCell<File>
+    fd: RefCell<File>,
+    block_size: usize,
+    num_blocks: usize,
 }
 
 impl Disk {
-    pub fn mount<P: AsRef<Path>>(filename: P) -> Result<Self> {
+    /// Opens an existing disk image, addressing it in blocks of `block_size` bytes.
+    pub fn mount<P: AsRef<Path>>(filename: P, block_size: usize) -> Result<Self> {
+        let fd = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(filename)?;
+        let num_blocks = (fd.metadata()?.len() / block_size as u64) as usize;
+
+        Ok(Self{fd:RefCell::new(fd), block_size, num_blocks})
+    }
+
+    /// Creates a zero-filled disk image of `num_blocks` blocks, discarding any
+    /// previous content of the file.
+    pub fn create<P: AsRef<Path>>(filename: P, block_size: usize, num_blocks: usize) -> Result<Self> {
         let fd = OpenOptions::new()
             .create(true)
+            .truncate(true)
             .read(true)
             .write(true)
             .open(filename)?;
+        fd.set_len(num_blocks as u64 * block_size as u64)?;
 
-        Ok(Self{fd:RefCell::new(fd)})
+        Ok(Self{fd:RefCell::new(fd), block_size, num_blocks})
     }
 
     pub fn unmount(self) {
         drop(self);
     }
+}
 
-    pub fn read_block(&self, block_num: usize, block: &mut Vec<u8>) -> Result<()>{
-        if block_num >= NUM_BLOCKS {
-            return Err(FSError::BlockError("Invalid block size".to_owned()));
-        }
-        let offset = block_num * BLOCK_SIZE;
+impl BlockDevice for Disk {
+    fn read_block(&self, block_num: usize, block: &mut [u8]) -> Result<()>{
+        self.check_block(block_num, block.len())?;
+        let offset = block_num as u64 * self.block_size as u64;
         let new_offset = self.fd.borrow_mut()
-                                .seek(SeekFrom::Start(offset as u64))?;
-        if offset != new_offset as usize {
+                                .seek(SeekFrom::Start(offset))?;
+        if offset != new_offset {
             return Err(FSError::SeekFailure);
         }
 
@@ -39,19 +81,77 @@ impl Disk {
         Ok(())
     }
 
-    pub fn write_block(&self, block_num: usize, block: &Vec<u8>) -> Result<()>{
-        if block_num >= NUM_BLOCKS {
-            return Err(FSError::BlockError("Invalid block size".to_owned()));
-        }
-        let offset = block_num * BLOCK_SIZE;
+    fn write_block(&self, block_num: usize, block: &[u8]) -> Result<()>{
+        self.check_block(block_num, block.len())?;
+        let offset = block_num as u64 * self.block_size as u64;
         let new_offset = self.fd.borrow_mut()
-                                .seek(SeekFrom::Start(offset as u64))?;
-        if offset != new_offset as usize {
+                                .seek(SeekFrom::Start(offset))?;
+        if offset != new_offset {
             return Err(FSError::SeekFailure);
         }
         self.fd.borrow_mut().write_all(block)?;
         Ok(())
     }
+
+    fn flush(&self) -> Result<()> {
+        self.fd.borrow_mut().sync_data()?;
+        Ok(())
+    }
+
+    fn block_count(&self) -> usize {
+        self.num_blocks
+    }
+
+    fn block_size(&self) -> usize {
+        self.block_size
+    }
+}
+
+/// MemDisk - a zero-filled block device held in memory.
+/// Clones share the same storage, so a device can be mounted again after an unmount.
+#[derive(Clone)]
+pub struct MemDisk {
+    data: Rc<RefCell<Vec<u8>>>,
+    block_size: usize,
+    num_blocks: usize,
+}
+
+impl MemDisk {
+    pub fn new(block_size: usize, num_blocks: usize) -> Self {
+        Self {
+            data: Rc::new(RefCell::new(vec![0u8; block_size * num_blocks])),
+            block_size,
+            num_blocks,
+        }
+    }
+}
+
+impl BlockDevice for MemDisk {
+    fn read_block(&self, block_num: usize, block: &mut [u8]) -> Result<()> {
+        self.check_block(block_num, block.len())?;
+        let offset = block_num * self.block_size;
+        block.copy_from_slice(&self.data.borrow()[offset..offset + block.len()]);
+        Ok(())
+    }
+
+    fn write_block(&self, block_num: usize, block: &[u8]) -> Result<()> {
+        self.check_block(block_num, block.len())?;
+        let offset = block_num * self.block_size;
+        self.data.borrow_mut()[offset..offset + block.len()].copy_from_slice(block);
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn block_count(&self) -> usize {
+        self.num_blocks
+    }
+
+    fn block_size(&self) -> usize {
+        self.block_size
+    }
 }
 
 #[cfg(test)]
@@ -60,7 +160,38 @@ mod test{
     use super::*;
     #[test]
     fn test() -> Result<()>{
-        let disk = Disk::mount("Disk")?;
+        let path = std::env::temp_dir().join(format!("sarari-disk-{}", std::process::id()));
+        let disk = Disk::create(&path, 512, 16)?;
+        let block = vec![0xABu8; 512];
+        disk.write_block(3, &block)?;
+        let mut buf = vec![0u8; 512];
+        disk.read_block(3, &mut buf)?;
+        assert_eq!(block, buf);
+        assert!(disk.write_block(16, &block).is_err());
+        disk.flush()?;
+        disk.unmount();
+
+        let disk = Disk::mount(&path, 512)?;
+        assert_eq!(disk.block_count(), 16);
+        disk.read_block(3, &mut buf)?;
+        assert_eq!(block, buf);
+        disk.unmount();
+        std::fs::remove_file(path)?;
         Ok(())
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_mem_disk() -> Result<()> {
+        let disk = MemDisk::new(512, 16);
+        let block = vec![0xABu8; 512];
+        disk.write_block(15, &block)?;
+        let mut buf = vec![0u8; 512];
+        disk.clone().read_block(15, &mut buf)?;
+        assert_eq!(block, buf);
+        disk.read_block(14, &mut buf)?;
+        assert_eq!(buf, vec![0u8; 512]);
+        assert!(disk.write_block(16, &block).is_err());
+        assert!(disk.write_block(0, &[0u8; 513]).is_err());
+        Ok(())
+    }
+}
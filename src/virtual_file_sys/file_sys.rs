@@ -1,13 +1,11 @@
-#[macro_use]
 use std::cell::RefCell;
 use std::cell::RefMut;
-use std::net::{TcpStream, UdpSocket};
-use serde::{Deserialize, Serialize};
-use crate::virtual_file_sys::disk::Disk;
+use std::path::Path;
+use crate::virtual_file_sys::disk::{BlockDevice, Disk};
 use crate::error::{FSError, Result};
 use super::block::*;
 use super::inode::*;
-
+use super::super_block::*;
 
 pub struct FileSys {
     wrapped_file_sys: WrappedFileSys,
@@ -15,191 +13,400 @@ pub struct FileSys {
 }
 
 impl FileSys {
-    pub fn mount() -> Result<Self>{
+    /// Formats the disk image and mounts the new, empty file system.
+    pub fn format<P: AsRef<Path>>(filename: P, options: &FormatOptions) -> Result<Self> {
+        Self::open(BasicFileSys::format(filename, options)?)
+    }
+
+    /// Mounts the file system already stored on the disk image.
+    pub fn mount<P: AsRef<Path>>(filename: P) -> Result<Self> {
+        Self::open(BasicFileSys::mount(filename)?)
+    }
+
+    /// Formats a block device using its own geometry and mounts the new file system.
+    pub fn format_device(disk: Box<dyn BlockDevice>, label: &str) -> Result<Self> {
+        Self::open(BasicFileSys::format_device(disk, label)?)
+    }
+
+    /// Mounts the file system already stored on a block device.
+    pub fn mount_device(disk: Box<dyn BlockDevice>) -> Result<Self> {
+        Self::open(BasicFileSys::mount_device(disk)?)
+    }
+
+    fn open(bfs: BasicFileSys) -> Result<Self> {
+        let root_dir_id = bfs.get_root_dir_id();
         let mut file_sys = Self{
-            wrapped_file_sys: WrappedFileSys::new()?,
-            curr_dir: 1,
+            wrapped_file_sys: WrappedFileSys::new(bfs),
+            curr_dir: root_dir_id,
         };
-        let home_dir = DirInode::retrieve(&file_sys.wrapped_file_sys, HOME_DIR_ID as usize)?;
+        let home_dir = DirInode::retrieve(&file_sys.wrapped_file_sys, root_dir_id)?;
         file_sys.set_working_dir(home_dir);
         Ok(file_sys)
     }
 
-    pub fn unmount(self){
+    pub fn unmount(self) -> Result<()> {
         self.wrapped_file_sys.into_inner().unmount()
-
     }
 
-    pub fn mkdir(&self, name: [u8; MAX_FNAME_SIZE]) -> Result<()> {
-        let mut working_dir = self.get_working_dir()?;
-        self.validate_before_new_entry(&working_dir, name)?;
-        let new_dir = DirInode::new(&self.wrapped_file_sys)?;
-        let entry = DirEntry::new(name, new_dir.get_id());
-        working_dir.add_dir_entry(&self.wrapped_file_sys,entry)?;
+    pub fn mkdir(&self, path: &str) -> Result<()> {
+        let (mut parent, name) = self.resolve_parent(path)?;
+        self.validate_before_new_entry(&parent, name)?;
+        let new_dir = DirInode::new(&self.wrapped_file_sys, parent.get_id())?;
+        let entry = DirEntry::new(name, new_dir.get_id(), InodeType::Dir);
+        if let Err(e) = parent.add_entry(&self.wrapped_file_sys, entry) {
+            new_dir.destroy(&self.wrapped_file_sys)?;
+            return Err(e);
+        }
+        // The ".." of the new directory links back to its parent.
+        parent.set_links(&self.wrapped_file_sys, parent.get_links() + 1)
 
-        Ok(())
     }
 
-    pub fn cd(&mut self, name: [u8; MAX_FNAME_SIZE]) -> Result<()>{
-        let mut working_dir = self.get_working_dir()?;
-        return match working_dir.get_dir_inode_entries()
-            .iter().find(|&e| e.get_name().eq(&name)) {
-            Some(entry) => {
-                self.set_working_dir(DirInode::retrieve(&self.wrapped_file_sys, entry.get_id())?);
-                Ok(())
-            },
-            None => { Err(FSError::FileNotFoundError) }
-        }
-
+    pub fn cd(&mut self, path: &str) -> Result<()>{
+        let dir = self.resolve_dir(path)?;
+        self.set_working_dir(dir);
+        Ok(())
     }
 
     pub fn home(&mut self) -> Result<()>{
+        let root_dir_id = self.wrapped_file_sys.file_sys().get_root_dir_id();
         self.set_working_dir(
-            DirInode::retrieve(&self.wrapped_file_sys,HOME_DIR_ID as usize)?);
+            DirInode::retrieve(&self.wrapped_file_sys, root_dir_id)?);
         Ok(())
     }
 
-    pub fn rmdir(&self, name: [u8; MAX_FNAME_SIZE]) -> Result<()>{
-        let mut working_dir = self.get_working_dir()?;
-        match working_dir.get_dir_inode_entries()
-            .iter().find(|&e| e.get_name().eq(&name)) {
-            Some(entry) => {
-                let dir = DirInode::retrieve(&self.wrapped_file_sys, entry.get_id())?;
-                match dir.has_free_entry() {
-                    true => {
-                        working_dir.remove_dir_entry(&self.wrapped_file_sys, entry.get_id())?;
-                    },
-                    false => {return Err(FSError::DirNotEmptyError);}
+    /// Removes the empty directory at `path`. When it is the working directory, the
+    /// working directory goes back to the root.
+    pub fn rmdir(&mut self, path: &str) -> Result<()>{
+        let (mut working_dir, name) = self.resolve_parent(path)?;
+        let entry = match working_dir.lookup(&self.wrapped_file_sys, name)? {
+            Some(entry) if entry.get_kind() == InodeType::Dir => entry,
+            Some(_) => return Err(FSError::NotDirError),
+            None => return Err(FSError::FileNotFoundError),
+        };
+        let dir = DirInode::retrieve(&self.wrapped_file_sys, entry.get_id())?;
+        match dir.is_empty(&self.wrapped_file_sys)? {
+            true => {
+                working_dir.remove_entry(&self.wrapped_file_sys, name)?;
+                working_dir.set_links(&self.wrapped_file_sys, working_dir.get_links() - 1)?;
+                dir.destroy(&self.wrapped_file_sys)?;
+                if entry.get_id() == self.curr_dir {
+                    self.curr_dir = self.wrapped_file_sys.file_sys().get_root_dir_id();
                 }
             },
-            None => {}
+            false => {return Err(FSError::DirNotEmptyError);}
         }
         Ok(())
     }
 
-    pub fn ls(&self) -> Result<String> {
-        let working_dir = self.get_working_dir()?;
+    pub fn ls(&self, path: &str) -> Result<String> {
+        let working_dir = self.resolve_dir(path)?;
+        let entries = working_dir.list(&self.wrapped_file_sys)?;
+        working_dir.accessed(&self.wrapped_file_sys)?;
         let mut names = Vec::new();
-        for entry in working_dir.get_dir_inode_entries() {
-            let mut name = std::str::from_utf8(&entry.get_name()).unwrap().to_owned();
+        for entry in entries.iter().filter(|e| e.get_kind() == InodeType::Dir) {
+            let mut name = entry.get_name().to_owned();
             name.push('/');
             names.push(name);
         }
-        for entry in working_dir.get_file_inode_entries() {
-            names.push(std::str::from_utf8(&entry.get_name()).unwrap().to_owned());
+        for entry in entries.iter().filter(|e| e.get_kind() != InodeType::Dir) {
+            let mut name = entry.get_name().to_owned();
+            if entry.get_kind() == InodeType::Symlink {
+                name.push('@');
+            }
+            names.push(name);
         }
         let res = names.join(" ");
         Ok(res)
 
     }
 
-    pub fn create(&self, name: [u8; MAX_FNAME_SIZE]) -> Result<()>{
-        let mut working_dir = self.get_working_dir()?;
+    pub fn create(&self, path: &str) -> Result<()>{
+        let (mut working_dir, name) = self.resolve_parent(path)?;
         self.validate_before_new_entry(&working_dir, name)?;
         let new_file = FileInode::new(&self.wrapped_file_sys)?;
-        working_dir.add_file_entry(&self.wrapped_file_sys, DirEntry::new(name, new_file.get_id()))
+        if let Err(e) = working_dir.add_entry(&self.wrapped_file_sys, DirEntry::new(name, new_file.get_id(), InodeType::File)) {
+            new_file.destroy(&self.wrapped_file_sys)?;
+            return Err(e);
+        }
+        Ok(())
     }
 
-    pub fn append(&self, name:[u8; MAX_FNAME_SIZE], data:&[u8]) -> Result<()> {
-        let mut working_dir = self.get_working_dir()?;
-        if let Some(_) = working_dir.get_dir_inode_entries()
-            .iter().find(|&e| e.get_name().eq(&name)) {
-            return Err(FSError::NotAFileError);
+    /// Gives the file at `existing` a second name, `new_path`. Both entries lead
+    /// to the same inode, which stays until the last of them is removed.
+    pub fn link(&self, existing: &str, new_path: &str) -> Result<()> {
+        let file = self.open_file(existing)?;
+        let (mut dir, name) = self.resolve_parent(new_path)?;
+        self.validate_before_new_entry(&dir, name)?;
+        // The count goes up first, so a failure part way leaves one link too many
+        // rather than an entry the count does not know about.
+        file.set_links(&self.wrapped_file_sys, file.get_links() + 1)?;
+        if let Err(e) = dir.add_entry(&self.wrapped_file_sys, DirEntry::new(name, file.get_id(), InodeType::File)) {
+            file.set_links(&self.wrapped_file_sys, file.get_links() - 1)?;
+            return Err(e);
         }
-        match working_dir.get_file_inode_entries()
-            .iter().find(|&e| e.get_name().eq(&name)) {
+        Ok(())
+    }
+
+    /// Adds `data` to the end of the file at `path`.
+    pub fn append(&self, path: &str, data:&[u8]) -> Result<()> {
+        let mut file = self.open_file(path)?;
+        let end = file.get_size();
+        file.write_at(&self.wrapped_file_sys, end, data)
+    }
+
+    /// Contents of the file at `path`, exactly as many bytes as the file holds.
+    pub fn cat(&self, path: &str) -> Result<Vec<u8>> {
+        let file = self.open_file(path)?;
+        let data = file.read_at(&self.wrapped_file_sys, 0, file.get_size())?;
+        file.accessed(&self.wrapped_file_sys)?;
+        Ok(data)
+    }
+
+    /// Reads up to `len` bytes of the file at `path` from byte `offset`.
+    pub fn read_at(&self, path: &str, offset: usize, len: usize) -> Result<Vec<u8>> {
+        let file = self.open_file(path)?;
+        let data = file.read_at(&self.wrapped_file_sys, offset, len)?;
+        file.accessed(&self.wrapped_file_sys)?;
+        Ok(data)
+    }
+
+    /// Writes `data` into the file at `path` at byte `offset`, growing the file
+    /// when it ends past the current end.
+    pub fn write_at(&self, path: &str, offset: usize, data: &[u8]) -> Result<()> {
+        self.open_file(path)?.write_at(&self.wrapped_file_sys, offset, data)
+    }
+
+    pub fn rm(&mut self, path: &str) -> Result<()> {
+        let (mut working_dir, name) = self.resolve_parent(path)?;
+        match working_dir.lookup(&self.wrapped_file_sys, name)? {
+            Some(entry) if entry.get_kind() == InodeType::Dir => {Err(FSError::NotAFileError)}
             Some(entry) => {
-                let mut file = FileInode::retrieve(&self.wrapped_file_sys, entry.get_id())?;
-                let new_total_size = data.len() + file.get_size();
-                let new_total_blocks = new_total_size / BLOCK_SIZE;
-                if new_total_blocks > MAX_DATA_BLOCKS {
-                    return Err(FSError::FileFullError);
-                }
-                let frag_size = file.internal_flag_size();
-                let mut pos = 0;
-                let mut last_block_id = None;
-                let mut last_block_data_backup = None;
-                if frag_size > 0 {         
-                    let last_block = file.get_blocks().last().unwrap();
-                    last_block_id = Some(last_block.get_id());
-                    last_block_data_backup = Some(last_block.get_data().clone());
-                    let mut fragmented_block_data = last_block.get_raw();
-                    pos = BLOCK_SIZE - frag_size;
-                    match data.len() <= pos {
-                        true => {fragmented_block_data.extend_from_slice(data); pos = data.len()},
-                        false => {fragmented_block_data.extend_from_slice(&data[..pos]);}
-                    }
+                // The entry goes first, so a failure part way leaks blocks rather
+                // than leaving the entry pointing at freed ones.
+                working_dir.remove_entry(&self.wrapped_file_sys, name)?;
+                self.open_entry(&entry)?.unlink(&self.wrapped_file_sys)
+            }
+            None => {Ok(())}
+        }
+    }
 
+    /// Removes the entry at `path` and, when it is a directory, everything below
+    /// it, depth first. Returns the number of entries removed. A failure part way
+    /// leaves the rest in place and comes back as `PartialRemoveError`, telling
+    /// how many entries were already gone.
+    pub fn rm_r(&mut self, path: &str) -> Result<usize> {
+        let (mut parent, name) = self.resolve_parent(path)?;
+        let entry = parent.lookup(&self.wrapped_file_sys, name)?
+            .ok_or(FSError::FileNotFoundError)?;
+        let removes_working_dir = entry.get_kind() == InodeType::Dir
+            && self.is_within(self.curr_dir, entry.get_id())?;
+        let mut removed = 0;
+        let result = self.remove_tree(&mut parent, &entry, &mut removed);
+        if removes_working_dir {
+            self.curr_dir = self.wrapped_file_sys.file_sys().get_root_dir_id();
+        }
+        match result {
+            Ok(()) => Ok(removed),
+            Err(e) if removed == 0 => Err(e),
+            Err(e) => Err(FSError::PartialRemoveError { removed, source: Box::new(e) }),
+        }
+    }
+
+    /// Removes `entry` from `parent`, emptying it first when it is a directory,
+    /// and counts every entry removed in `removed`.
+    fn remove_tree(&self, parent: &mut DirInode, entry: &DirEntry, removed: &mut usize) -> Result<()> {
+        match entry.get_kind() {
+            InodeType::Dir => {
+                let mut dir = DirInode::retrieve(&self.wrapped_file_sys, entry.get_id())?;
+                for child in dir.list(&self.wrapped_file_sys)? {
+                    self.remove_tree(&mut dir, &child, removed)?;
                 }
-                if pos < data.len() {
-                    let mut new_blocks = vec![];
-                    for chunk in data[pos..].chunks(BLOCK_SIZE) {
-                        match DataBlock::new(&self.wrapped_file_sys) {
-                            Ok(new_block) => {
-                                new_block.write_and_set_raw_block(&self.wrapped_file_sys, chunk.to_vec())?;
-                                new_blocks.push(new_block);
-                            },
-                            Err(e) => {
-                                if last_block_data_backup.is_some() {
-                                    self.wrapped_file_sys.file_sys().write_block(last_block_id.unwrap(), &last_block_data_backup.unwrap())?;
-                                }                
-                                for new_block in new_blocks {
-                                    self.wrapped_file_sys.file_sys().reclaim_block(new_block.get_id())?;
-                                }
-                                return Err(e)
-                            },
-                        }           
-                    }
-                    for new_block in new_blocks {
-                        file.add_block(&self.wrapped_file_sys, new_block)?;
-                    }
-                }
-                file.set_size(&self.wrapped_file_sys, new_total_blocks)?;
+                parent.remove_entry(&self.wrapped_file_sys, entry.get_name())?;
+                parent.set_links(&self.wrapped_file_sys, parent.get_links() - 1)?;
+                dir.destroy(&self.wrapped_file_sys)?;
+            },
+            InodeType::File | InodeType::Symlink => {
+                let file = self.open_entry(entry)?;
+                parent.remove_entry(&self.wrapped_file_sys, entry.get_name())?;
+                file.unlink(&self.wrapped_file_sys)?;
             },
-            None => {return Err(FSError::FileNotFoundError);}
         }
+        *removed += 1;
         Ok(())
     }
 
+    /// Whether directory `ino` is `ancestor` or somewhere below it.
+    fn is_within(&self, ino: usize, ancestor: usize) -> Result<bool> {
+        let root_dir_id = self.wrapped_file_sys.file_sys().get_root_dir_id();
+        let mut ino = ino;
+        while ino != root_dir_id {
+            if ino == ancestor {
+                return Ok(true);
+            }
+            ino = DirInode::retrieve(&self.wrapped_file_sys, ino)?.get_parent();
+        }
+        Ok(ino == ancestor)
+    }
 
-    pub fn cat(&self, name: [u8; MAX_FNAME_SIZE]) -> Result<String> {
-        let max_file_size = BLOCK_SIZE * BLOCK_SIZE;
-        let mut working_dir = self.get_working_dir()?;
-        if let Some(entry) = working_dir.get_dir_inode_entries()
-            .iter().find(|&e| e.get_name().eq(&name)) {
-            return Err(FSError::NotAFileError);
+    /// Moves the entry at `src_path` to `dst_path`, within a directory or across
+    /// directories. An existing file at `dst_path` is replaced, losing a link, and
+    /// so is an empty directory when a directory is moved, the working directory
+    /// going back to the root if it was the one replaced. The entry is linked
+    /// under its new name before the old one goes, so it is never missing from
+    /// both places. Moving a name onto another link to the same file does nothing.
+    pub fn rename(&mut self, src_path: &str, dst_path: &str) -> Result<()> {
+        let (src_parent, src_name) = self.resolve_parent(src_path)?;
+        let entry = src_parent.lookup(&self.wrapped_file_sys, src_name)?
+            .ok_or(FSError::FileNotFoundError)?;
+        let (mut dst_parent, dst_name) = self.resolve_parent(dst_path)?;
+        let src_parent_id = src_parent.get_id();
+        let dst_parent_id = dst_parent.get_id();
+        if src_parent_id == dst_parent_id && src_name == dst_name {
+            return Ok(());
+        }
+        // A directory cannot end up inside its own subtree.
+        if entry.get_kind() == InodeType::Dir && self.is_within(dst_parent_id, entry.get_id())? {
+            return Err(FSError::InvalidMoveError);
         }
 
-        match  working_dir.get_file_inode_entries()
-            .iter().find(|&e| e.get_name().eq(&name)) {
-            Some(entry) => {
-                let file = FileInode::retrieve(&self.wrapped_file_sys, entry.get_id())?;
-                let mut content = String::new();
-                for block in file.get_blocks().iter() {
-                    content.push_str(std::str::from_utf8(&block.get_data()).unwrap());
+        let moved = DirEntry::new(dst_name, entry.get_id(), entry.get_kind());
+        let replaced = dst_parent.lookup(&self.wrapped_file_sys, dst_name)?;
+        match &replaced {
+            Some(existing) if existing.get_id() == entry.get_id() => return Ok(()),
+            Some(existing) => {
+                match (entry.get_kind() == InodeType::Dir, existing.get_kind() == InodeType::Dir) {
+                    (false, true) => return Err(FSError::NotAFileError),
+                    (true, false) => return Err(FSError::NotDirError),
+                    (true, true) => {
+                        let existing = DirInode::retrieve(&self.wrapped_file_sys, existing.get_id())?;
+                        if !existing.is_empty(&self.wrapped_file_sys)? {
+                            return Err(FSError::DirNotEmptyError);
+                        }
+                    },
+                    (false, false) => {},
+                }
+                dst_parent.replace_entry(&self.wrapped_file_sys, moved)?;
+            },
+            None => {
+                if !dst_parent.has_room_for(&self.wrapped_file_sys, dst_name)? {
+                    return Err(FSError::DirFullError);
                 }
-                return Ok(content);
+                dst_parent.add_entry(&self.wrapped_file_sys, moved)?;
             },
-            None => {return Err(FSError::FileNotFoundError);}
         }
-    }
 
-    pub fn rm(&mut self, name: [u8; MAX_FNAME_SIZE]) -> Result<()> {
-        let mut working_dir = self.get_working_dir()?;
-        if let Some(entry) = working_dir.get_dir_inode_entries()
-            .iter().find(|&e| e.get_name().eq(&name)) {
-            return Err(FSError::NotAFileError);
+        // Adding the entry may have changed the blocks of the source directory when
+        // it is the destination too, so it is read again.
+        let mut src_parent = DirInode::retrieve(&self.wrapped_file_sys, src_parent_id)?;
+        src_parent.remove_entry(&self.wrapped_file_sys, src_name)?;
+        // A directory links to its parent through "..", and so does the empty
+        // directory it may replace.
+        let replaced_dir = replaced.as_ref().is_some_and(|old| old.get_kind() == InodeType::Dir);
+        if entry.get_kind() == InodeType::Dir && src_parent_id != dst_parent_id {
+            src_parent.set_links(&self.wrapped_file_sys, src_parent.get_links() - 1)?;
+            if !replaced_dir {
+                dst_parent.set_links(&self.wrapped_file_sys, dst_parent.get_links() + 1)?;
+            }
+            let mut dir = DirInode::retrieve(&self.wrapped_file_sys, entry.get_id())?;
+            dir.set_parent(&self.wrapped_file_sys, dst_parent_id)?;
+        } else if replaced_dir {
+            src_parent.set_links(&self.wrapped_file_sys, src_parent.get_links() - 1)?;
         }
-        match  working_dir.get_file_inode_entries()
-            .iter().find(|&e| e.get_name().eq(&name)) {
-            Some(entry) => {
-                working_dir.remove_file_entry(&self.wrapped_file_sys, entry.get_id())?;
+        update_inode(&self.wrapped_file_sys, entry.get_id(), Inode::changed)?;
+        match replaced {
+            Some(old) if old.get_kind() == InodeType::Dir => {
+                DirInode::retrieve(&self.wrapped_file_sys, old.get_id())?.destroy(&self.wrapped_file_sys)?;
+                if old.get_id() == self.curr_dir {
+                    self.curr_dir = self.wrapped_file_sys.file_sys().get_root_dir_id();
+                }
+                Ok(())
             },
-            None => {}
+            Some(old) => self.open_entry(&old)?.unlink(&self.wrapped_file_sys),
+            None => Ok(()),
+        }
+    }
+
+    /// Metadata of the file or directory at `path`, following a symbolic link
+    /// there to what it points at.
+    pub fn stat(&self, path: &str) -> Result<Stat> {
+        Stat::of(&self.wrapped_file_sys, self.resolve_ino(path, true)?.0)
+    }
+
+    /// Metadata of the entry at `path`, the link itself for a symbolic link.
+    pub fn lstat(&self, path: &str) -> Result<Stat> {
+        Stat::of(&self.wrapped_file_sys, self.resolve_ino(path, false)?.0)
+    }
+
+    /// Creates a symbolic link at `link_path` pointing at `target`. The target is
+    /// kept as it is, and need not exist; a relative one is resolved from the
+    /// directory holding the link whenever the link is followed.
+    pub fn symlink(&self, target: &str, link_path: &str) -> Result<()> {
+        if target.len() > MAX_SYMLINK_SIZE {
+            return Err(FSError::FileNameTooLongError);
+        }
+        if target.is_empty() || target.contains('\0') {
+            return Err(FSError::InvalidFileNameError);
+        }
+        let (mut dir, name) = self.resolve_parent(link_path)?;
+        self.validate_before_new_entry(&dir, name)?;
+        let link = FileInode::new_symlink(&self.wrapped_file_sys, target)?;
+        if let Err(e) = dir.add_entry(&self.wrapped_file_sys, DirEntry::new(name, link.get_id(), InodeType::Symlink)) {
+            link.destroy(&self.wrapped_file_sys)?;
+            return Err(e);
         }
         Ok(())
     }
 
+    /// Target of the symbolic link at `path`.
+    pub fn readlink(&self, path: &str) -> Result<String> {
+        let (dir, name) = self.resolve_parent(path)?;
+        match dir.lookup(&self.wrapped_file_sys, name)? {
+            Some(entry) if entry.get_kind() == InodeType::Symlink => self.read_target(entry.get_id()),
+            Some(_) => Err(FSError::NotSymlinkError),
+            None => Err(FSError::FileNotFoundError),
+        }
+    }
+
+    /// Entries of the directory at `path`, one per line with their type and
+    /// permissions, link count, owner, size and modification time.
+    pub fn ls_long(&self, path: &str) -> Result<String> {
+        let dir = self.resolve_dir(path)?;
+        let mut entries = dir.list(&self.wrapped_file_sys)?;
+        dir.accessed(&self.wrapped_file_sys)?;
+        // Directories first, as `ls` lists them.
+        entries.sort_by_key(|e| e.get_kind() != InodeType::Dir);
+        let mut lines = Vec::new();
+        for entry in entries {
+            let stat = Stat::of(&self.wrapped_file_sys, entry.get_id())?;
+            let mut line = format!("{} {:>3} {:>5} {:>5} {:>10} {} {}",
+                stat.mode_string(), stat.links, stat.uid, stat.gid, stat.size,
+                format_time(stat.mtime), entry.get_name());
+            if entry.get_kind() == InodeType::Symlink {
+                line.push_str(" -> ");
+                line.push_str(&self.read_target(entry.get_id())?);
+            }
+            lines.push(line);
+        }
+        Ok(lines.join("\n"))
+    }
+
+    /// Sets the permission bits of the file or directory at `path`.
+    pub fn chmod(&self, path: &str, mode: u16) -> Result<()> {
+        update_inode(&self.wrapped_file_sys, self.resolve_ino(path, true)?.0, |inode| inode.set_mode(mode))
+    }
+
+    /// Sets the owner of the file or directory at `path`.
+    pub fn chown(&self, path: &str, uid: u32, gid: u32) -> Result<()> {
+        update_inode(&self.wrapped_file_sys, self.resolve_ino(path, true)?.0, |inode| inode.set_owner(uid, gid))
+    }
+
+    /// Sets the access and modification times of the file or directory at `path`,
+    /// such as to carry them over from a copy elsewhere.
+    pub fn set_times(&self, path: &str, atime: u64, mtime: u64) -> Result<()> {
+        update_inode(&self.wrapped_file_sys, self.resolve_ino(path, true)?.0, |inode| inode.set_times(atime, mtime))
+    }
+
     pub fn set_working_dir(&mut self, dir: DirInode) {
         self.curr_dir = dir.get_id();
     }
@@ -208,14 +415,128 @@ impl FileSys {
         DirInode::retrieve(&self.wrapped_file_sys, self.curr_dir)
     }
 
-    pub fn validate_before_new_entry(&self, dir: &DirInode, name: [u8; MAX_FNAME_SIZE]) -> Result<()> {
-        if !dir.has_free_entry() {
-            return Err(FSError::DirFullError);
+    /// Cuts the file at `path` down, or extends it, to `len` bytes, freeing the blocks past the new end.
+    pub fn truncate(&self, path: &str, len: usize) -> Result<()> {
+        self.open_file(path)?.truncate(&self.wrapped_file_sys, len)
+    }
+
+    /// Reserves blocks for the first `len` bytes of the file at `path`, growing it to `len` bytes if shorter.
+    pub fn fallocate(&self, path: &str, len: usize) -> Result<()> {
+        self.open_file(path)?.fallocate(&self.wrapped_file_sys, len)
+    }
+
+    /// File `path` leads to, following a symbolic link there.
+    fn open_file(&self, path: &str) -> Result<FileInode> {
+        let (dir, name) = self.resolve_parent(path)?;
+        let entry = dir.lookup(&self.wrapped_file_sys, name)?
+            .ok_or(FSError::FileNotFoundError)?;
+        match self.follow(dir.get_id(), entry, &mut 0)? {
+            (ino, InodeType::File) => FileInode::retrieve(&self.wrapped_file_sys, ino),
+            _ => Err(FSError::NotAFileError),
         }
-        if dir.get_dir_inode_entries().iter().find(|&e| e.get_name().eq(&name)).is_some() {
-            return Err(FSError::FileExistsError)
+    }
+
+    /// File or symbolic link `entry` names.
+    fn open_entry(&self, entry: &DirEntry) -> Result<FileInode> {
+        match entry.get_kind() {
+            InodeType::Symlink => FileInode::retrieve_symlink(&self.wrapped_file_sys, entry.get_id()),
+            _ => FileInode::retrieve(&self.wrapped_file_sys, entry.get_id()),
         }
-        if dir.get_file_inode_entries().iter().find(|&e| e.get_name().eq(&name)).is_some() {
+    }
+
+    /// Path the symbolic link `ino` points at.
+    fn read_target(&self, ino: usize) -> Result<String> {
+        let link = FileInode::retrieve_symlink(&self.wrapped_file_sys, ino)?;
+        let target = link.read_at(&self.wrapped_file_sys, 0, link.get_size())?;
+        String::from_utf8(target).map_err(|_| FSError::FileSysError)
+    }
+
+    /// Directory `path` leads to, starting from the root when it begins with '/'
+    /// and from the working directory otherwise. "." stays in a directory, ".."
+    /// goes up to its parent, the empty components of repeated slashes are skipped
+    /// and symbolic links are followed.
+    pub fn resolve_dir(&self, path: &str) -> Result<DirInode> {
+        self.walk_dir(self.curr_dir, path, &mut 0)
+    }
+
+    /// Inode `path` leads to and its type. A symbolic link at the end of the path
+    /// is followed when `follow` is set.
+    fn resolve_ino(&self, path: &str, follow: bool) -> Result<(usize, InodeType)> {
+        self.walk(self.curr_dir, path, follow, &mut 0)
+    }
+
+    /// Directory holding the last component of `path`, along with that component.
+    /// The component has to be a valid entry name, so it cannot be "." or "..".
+    pub fn resolve_parent<'a>(&self, path: &'a str) -> Result<(DirInode, &'a str)> {
+        let (dir_path, name) = split_path(path);
+        validate_name(name)?;
+        Ok((self.resolve_dir(dir_path)?, name))
+    }
+
+    /// Directory `path` leads to from directory `from`, following symbolic links.
+    /// `from` is only read for a relative path. `follows` counts the links followed
+    /// while resolving the whole path.
+    fn walk_dir(&self, from: usize, path: &str, follows: &mut usize) -> Result<DirInode> {
+        let start = match path.starts_with('/') {
+            true => self.wrapped_file_sys.file_sys().get_root_dir_id(),
+            false => from,
+        };
+        let mut dir = DirInode::retrieve(&self.wrapped_file_sys, start)?;
+        for name in path.split('/') {
+            dir = match name {
+                "" | "." => dir,
+                ".." => DirInode::retrieve(&self.wrapped_file_sys, dir.get_parent())?,
+                name => {
+                    let entry = dir.lookup(&self.wrapped_file_sys, name)?
+                        .ok_or(FSError::FileNotFoundError)?;
+                    match self.follow(dir.get_id(), entry, follows)? {
+                        (ino, InodeType::Dir) => DirInode::retrieve(&self.wrapped_file_sys, ino)?,
+                        _ => return Err(FSError::NotDirError),
+                    }
+                },
+            };
+        }
+        Ok(dir)
+    }
+
+    /// Inode and type `path` leads to from directory `from`. Symbolic links on the
+    /// way are followed, and so is one at the end when `follow` is set.
+    fn walk(&self, from: usize, path: &str, follow: bool, follows: &mut usize) -> Result<(usize, InodeType)> {
+        match split_path(path) {
+            (_, "" | "." | "..") => Ok((self.walk_dir(from, path, follows)?.get_id(), InodeType::Dir)),
+            (dir_path, name) => {
+                let dir = self.walk_dir(from, dir_path, follows)?;
+                let entry = dir.lookup(&self.wrapped_file_sys, name)?
+                    .ok_or(FSError::FileNotFoundError)?;
+                match follow {
+                    true => self.follow(dir.get_id(), entry, follows),
+                    false => Ok((entry.get_id(), entry.get_kind())),
+                }
+            },
+        }
+    }
+
+    /// Inode and type `entry` of directory `dir` leads to: its own, or for a
+    /// symbolic link those of whatever its target leads to, with a relative
+    /// target starting from `dir`.
+    fn follow(&self, dir: usize, entry: DirEntry, follows: &mut usize) -> Result<(usize, InodeType)> {
+        if entry.get_kind() != InodeType::Symlink {
+            return Ok((entry.get_id(), entry.get_kind()));
+        }
+        *follows += 1;
+        if *follows > MAX_SYMLINK_FOLLOWS {
+            return Err(FSError::SymlinkLoopError);
+        }
+        let target = self.read_target(entry.get_id())?;
+        self.walk(dir, &target, true, follows)
+    }
+
+    pub fn validate_before_new_entry(&self, dir: &DirInode, name: &str) -> Result<()> {
+        validate_name(name)?;
+        if !dir.has_room_for(&self.wrapped_file_sys, name)? {
+            return Err(FSError::DirFullError);
+        }
+        if dir.lookup(&self.wrapped_file_sys, name)?.is_some() {
             return Err(FSError::FileExistsError)
         }
         Ok(())
@@ -223,16 +544,27 @@ impl FileSys {
 }
 
 
+/// Splits `path` into the path of the directory holding its last component and
+/// that component, ignoring trailing slashes.
+fn split_path(path: &str) -> (&str, &str) {
+    let path = path.trim_end_matches('/');
+    match path.rsplit_once('/') {
+        Some(("", name)) => ("/", name),
+        Some((dir_path, name)) => (dir_path, name),
+        None => ("", path),
+    }
+}
+
 pub struct WrappedFileSys {
     bfs: RefCell<BasicFileSys>
 }
 
 impl WrappedFileSys {
-    pub fn new() -> Result<Self> {
-        Ok(Self{bfs:RefCell::new(BasicFileSys::mount()?)})
+    pub fn new(bfs: BasicFileSys) -> Self {
+        Self{bfs:RefCell::new(bfs)}
     }
 
-    pub fn file_sys(&self) -> RefMut<BasicFileSys> {
+    pub fn file_sys(&self) -> RefMut<'_, BasicFileSys> {
         self.bfs.borrow_mut()
     }
 
@@ -242,79 +574,1283 @@ impl WrappedFileSys {
 }
 
 pub struct BasicFileSys {
-    disk: Disk
+    disk: Box<dyn BlockDevice>,
+    super_block: SuperBlock,
+    /// In-memory copy of the free-block bitmap; every change is written through to disk.
+    bitmap: Vec<u8>,
+    /// In-memory copy of the inode bitmap, kept the same way.
+    inode_bitmap: Vec<u8>,
+    /// Block the next search for free space starts from.
+    next_free: usize,
 }
 
 impl BasicFileSys {
-    pub fn mount() -> Result<Self>{
-        let disk = Disk::mount("DISK")?;
-
-        let mut super_block = vec![0u8; BLOCK_SIZE];
-        super_block[0]=0x3;
-        disk.write_block(0, &super_block)?;
-
-        let dir_block = DirBlock::new();
-       
-        disk.write_block(1, &dir_block.to_bytes()?)?;
-        let mut buf = vec![0u8;BLOCK_SIZE];
-        disk.read_block(1, &mut buf);
-        let dir = bincode::deserialize::<DirBlock>(&buf)?;
-        let data_block = vec![0u8; BLOCK_SIZE];;
-        for i in 2..NUM_BLOCKS {
-            disk.write_block(i, &data_block);
-        }
-        Ok(Self{disk})
-    }
-
-    pub fn unmount(self) {
-        self.disk.unmount();
-    }
-
-    pub fn get_free_block(&self) -> Option<usize>{
-        let mut super_block = vec![0u8; BLOCK_SIZE];
-        self.disk.read_block(0,&mut super_block);
-
-        for byte in 0..BLOCK_SIZE {
-            if super_block[byte] != 0xFF {
-                for bit in 0..8 {
-                    let mask = 1 << bit;
-                    if mask & !super_block[byte] > 0 {
-                        super_block[byte] |= mask;
-                        self.disk.write_block(0, &super_block);
-                        return Some(byte * 8 + bit);
-                    }
+    /// Writes an empty file system to the disk image, destroying anything stored on it.
+    pub fn format<P: AsRef<Path>>(filename: P, options: &FormatOptions) -> Result<Self>{
+        // Check the geometry before touching the file.
+        SuperBlock::new(options)?;
+        let disk = Disk::create(filename, options.block_size, options.num_blocks)?;
+        Self::format_with(Box::new(disk), options)
+    }
+
+    /// Writes an empty file system covering the whole block device.
+    pub fn format_device(disk: Box<dyn BlockDevice>, label: &str) -> Result<Self>{
+        let options = FormatOptions {
+            block_size: disk.block_size(),
+            num_blocks: disk.block_count(),
+            label: label.to_owned(),
+            ..FormatOptions::default()
+        };
+        Self::format_with(disk, &options)
+    }
+
+    fn format_with(disk: Box<dyn BlockDevice>, options: &FormatOptions) -> Result<Self>{
+        let super_block = SuperBlock::new(options)?;
+        let block_size = super_block.get_block_size();
+        let num_blocks = super_block.get_num_blocks();
+        let first_data_block = super_block.get_first_data_block();
+
+        // A device is not necessarily zero-filled.
+        let zero_block = vec![0u8; block_size];
+        for block_num in 0..first_data_block {
+            disk.write_block(block_num, &zero_block)?;
+        }
+
+        disk.write_block(SUPER_BLOCK_ID, &super_block.to_bytes()?)?;
+
+        // Mark the metadata blocks and the padding past the end of the disk as used.
+        let bitmap_bits = super_block.get_bitmap_blocks() * block_size * 8;
+        let mut bitmap = vec![0u8; bitmap_bits / 8];
+        mark_bits(&mut bitmap, 0, first_data_block, true);
+        mark_bits(&mut bitmap, num_blocks, bitmap_bits - num_blocks, true);
+        // Inode 0 is never handed out.
+        let num_inodes = super_block.get_num_inodes();
+        let inode_bitmap_bits = super_block.get_inode_bitmap_blocks() * block_size * 8;
+        let mut inode_bitmap = vec![0u8; inode_bitmap_bits / 8];
+        mark_bits(&mut inode_bitmap, 0, 1, true);
+        mark_bits(&mut inode_bitmap, num_inodes, inode_bitmap_bits - num_inodes, true);
+
+        let mut bfs = Self{disk, super_block, bitmap, inode_bitmap, next_free: first_data_block};
+        bfs.write_bitmap(false, 0, num_blocks)?;
+        bfs.write_bitmap(true, 0, num_inodes)?;
+
+        // The root directory takes the first inode and the first data block.
+        let root_dir_id = bfs.allocate_inode()?.ok_or(FSError::DiskFullError)?;
+        let root_block = bfs.get_free_block()?.ok_or(FSError::DiskFullError)?;
+        bfs.write_block(root_block, &DirBlock::new(block_size).to_bytes()?)?;
+        // The root is its own parent, so ".." leads back to it.
+        bfs.write_inode(root_dir_id, &Inode::new_dir(root_block, block_size, root_dir_id).to_bytes()?)?;
+        bfs.disk.flush()?;
+        Ok(bfs)
+    }
+
+    /// Opens the disk image as it is, refusing images that were never
+    /// formatted or were formatted by an incompatible version.
+    pub fn mount<P: AsRef<Path>>(filename: P) -> Result<Self>{
+        // The block size is only known once the super block has been read,
+        // and the super block always fits in the smallest block size.
+        let probe = Disk::mount(&filename, MIN_BLOCK_SIZE)?;
+        if probe.block_count() == 0 {
+            return Err(FSError::NotFormattedError);
+        }
+        let mut super_block = vec![0u8; MIN_BLOCK_SIZE];
+        probe.read_block(SUPER_BLOCK_ID, &mut super_block)?;
+        let disk_size = (probe.block_count() * MIN_BLOCK_SIZE) as u64;
+        probe.unmount();
+        let super_block = SuperBlock::from_bytes(&super_block)
+            .map_err(|_| FSError::NotFormattedError)?;
+        super_block.validate(disk_size)?;
+
+        let disk = Disk::mount(&filename, super_block.get_block_size())?;
+        Self::mount_device(Box::new(disk))
+    }
+
+    /// Opens the file system stored on a block device, refusing devices whose
+    /// block size differs from the one the file system was formatted with.
+    pub fn mount_device(disk: Box<dyn BlockDevice>) -> Result<Self>{
+        if disk.block_count() == 0 {
+            return Err(FSError::NotFormattedError);
+        }
+        let mut super_block = vec![0u8; disk.block_size()];
+        disk.read_block(SUPER_BLOCK_ID, &mut super_block)?;
+        let mut super_block = SuperBlock::from_bytes(&super_block)
+            .map_err(|_| FSError::NotFormattedError)?;
+        super_block.validate(disk.block_count() as u64 * disk.block_size() as u64)?;
+        if super_block.get_block_size() != disk.block_size() {
+            return Err(FSError::InvalidGeometryError(
+                format!("block size {} on a device of {} byte blocks", super_block.get_block_size(), disk.block_size())));
+        }
+
+        super_block.touch_mounted();
+        disk.write_block(SUPER_BLOCK_ID, &super_block.to_bytes()?)?;
+
+        let block_size = super_block.get_block_size();
+        let mut bitmap = vec![0u8; super_block.get_bitmap_blocks() * block_size];
+        for (i, bitmap_block) in bitmap.chunks_mut(block_size).enumerate() {
+            disk.read_block(super_block.get_bitmap_start() + i, bitmap_block)?;
+        }
+        let mut inode_bitmap = vec![0u8; super_block.get_inode_bitmap_blocks() * block_size];
+        for (i, bitmap_block) in inode_bitmap.chunks_mut(block_size).enumerate() {
+            disk.read_block(super_block.get_inode_bitmap_start() + i, bitmap_block)?;
+        }
+        let next_free = super_block.get_first_data_block();
+        Ok(Self{disk, super_block, bitmap, inode_bitmap, next_free})
+    }
+
+    pub fn unmount(self) -> Result<()> {
+        self.disk.flush()
+    }
+
+    pub fn get_super_block(&self) -> &SuperBlock {
+        &self.super_block
+    }
+
+    pub fn get_root_dir_id(&self) -> usize {
+        self.super_block.get_root_inode()
+    }
+
+    pub fn block_size(&self) -> usize {
+        self.super_block.get_block_size()
+    }
+
+    pub fn num_blocks(&self) -> usize {
+        self.super_block.get_num_blocks()
+    }
+
+    #[inline]
+    fn is_free(&self, block_num: usize) -> bool {
+        self.bitmap[block_num / 8] & (1 << (block_num % 8)) == 0
+    }
+
+    /// Number of free blocks in the run starting at `start`, up to `max_len`.
+    fn free_run(&self, start: usize, max_len: usize) -> usize {
+        let end = self.num_blocks().min(start + max_len);
+        (start..end).take_while(|&block_num| self.is_free(block_num)).count()
+    }
+
+    /// Marks `len` blocks from `start` as used or free and writes the touched bitmap blocks back.
+    fn mark_blocks(&mut self, start: usize, len: usize, used: bool) -> Result<()> {
+        if start + len > self.num_blocks() {
+            return Err(FSError::BlockError("Invalid block size".to_owned()));
+        }
+        mark_bits(&mut self.bitmap, start, len, used);
+        self.write_bitmap(false, start, len)
+    }
+
+    /// Writes the blocks of the block bitmap, or of the inode bitmap, holding bits `start..start + len`.
+    fn write_bitmap(&self, inodes: bool, start: usize, len: usize) -> Result<()> {
+        if len == 0 {
+            return Ok(());
+        }
+        let (bitmap, bitmap_start) = match inodes {
+            true => (&self.inode_bitmap, self.super_block.get_inode_bitmap_start()),
+            false => (&self.bitmap, self.super_block.get_bitmap_start()),
+        };
+        let block_size = self.block_size();
+        let bits_per_block = block_size * 8;
+        for i in start / bits_per_block..=(start + len - 1) / bits_per_block {
+            self.disk.write_block(bitmap_start + i, &bitmap[i * block_size..(i + 1) * block_size])?;
+        }
+        Ok(())
+    }
+
+    /// Allocates a run of up to `len` contiguous blocks and returns its first block and length.
+    /// The run continues at `goal` when that block is free; otherwise the first run of
+    /// `len` free blocks is used, falling back to the longest shorter run when the free
+    /// space is too fragmented.
+    pub fn allocate_extent(&mut self, goal: Option<usize>, len: usize) -> Result<Option<(usize, usize)>> {
+        let num_blocks = self.num_blocks();
+        if len == 0 {
+            return Ok(None);
+        }
+        if let Some(goal) = goal.filter(|&goal| goal < num_blocks && self.is_free(goal)) {
+            let run = self.free_run(goal, len);
+            return self.take_extent(goal, run);
+        }
+
+        let mut best: Option<(usize, usize)> = None;
+        let mut block_num = self.next_free % num_blocks;
+        let mut scanned = 0;
+        while scanned < num_blocks {
+            if block_num.is_multiple_of(8) && self.bitmap[block_num / 8] == 0xFF {
+                block_num += 8;
+                scanned += 8;
+            } else if self.is_free(block_num) {
+                let run = self.free_run(block_num, len);
+                if run == len {
+                    return self.take_extent(block_num, run);
+                }
+                if best.is_none_or(|(_, best_run)| run > best_run) {
+                    best = Some((block_num, run));
                 }
+                block_num += run;
+                scanned += run;
+            } else {
+                block_num += 1;
+                scanned += 1;
+            }
+            if block_num >= num_blocks {
+                block_num = 0;
             }
         }
-        None
+        match best {
+            Some((start, run)) => self.take_extent(start, run),
+            None => Ok(None),
+        }
+    }
+
+    fn take_extent(&mut self, start: usize, len: usize) -> Result<Option<(usize, usize)>> {
+        self.mark_blocks(start, len, true)?;
+        self.next_free = start + len;
+        Ok(Some((start, len)))
+    }
+
+    pub fn get_free_block(&mut self) -> Result<Option<usize>>{
+        Ok(self.allocate_extent(None, 1)?.map(|(start, _)| start))
+    }
+
+    pub fn count_free_blocks(&self) -> Result<usize> {
+        Ok((0..self.num_blocks()).filter(|&block_num| self.is_free(block_num)).count())
     }
 
-    pub fn reclaim_block(&self, block_num: usize) -> Result<()>{
-        let mut super_block = vec![0u8; BLOCK_SIZE];
-        self.disk.read_block(0, &mut super_block)?;
+    pub fn reclaim_block(&mut self, block_num: usize) -> Result<()>{
+        self.mark_blocks(block_num, 1, false)
+    }
+
+    pub fn reclaim_extent(&mut self, start: usize, len: usize) -> Result<()> {
+        self.mark_blocks(start, len, false)
+    }
+
+    /// Takes the lowest free inode number.
+    pub fn allocate_inode(&mut self) -> Result<Option<usize>> {
+        let num_inodes = self.num_inodes();
+        let ino = (ROOT_INO..num_inodes).find(|&ino| self.inode_bitmap[ino / 8] & (1 << (ino % 8)) == 0);
+        if let Some(ino) = ino {
+            mark_bits(&mut self.inode_bitmap, ino, 1, true);
+            self.write_bitmap(true, ino, 1)?;
+        }
+        Ok(ino)
+    }
+
+    pub fn reclaim_inode(&mut self, ino: usize) -> Result<()> {
+        self.check_inode(ino)?;
+        mark_bits(&mut self.inode_bitmap, ino, 1, false);
+        // Clear the slot so the inode no longer shows up as one.
+        self.write_inode(ino, &[0u8; INODE_SIZE])?;
+        self.write_bitmap(true, ino, 1)
+    }
+
+    pub fn count_free_inodes(&self) -> Result<usize> {
+        Ok((0..self.num_inodes()).filter(|&ino| self.inode_bitmap[ino / 8] & (1 << (ino % 8)) == 0).count())
+    }
+
+    pub fn num_inodes(&self) -> usize {
+        self.super_block.get_num_inodes()
+    }
+
+    fn check_inode(&self, ino: usize) -> Result<()> {
+        if ino == 0 || ino >= self.num_inodes() {
+            return Err(FSError::BlockError(format!("Invalid inode {ino}")));
+        }
+        Ok(())
+    }
+
+    /// Block of the inode table holding inode `ino`, and the offset of the inode in it.
+    fn inode_location(&self, ino: usize) -> (usize, usize) {
+        let inodes_per_block = self.block_size() / INODE_SIZE;
+        (self.super_block.get_inode_table_start() + ino / inodes_per_block,
+         (ino % inodes_per_block) * INODE_SIZE)
+    }
 
-        let byte = block_num / 8;
-        let bit = block_num % 8;
-        let mask = !(1<<bit);
-        super_block[byte] &= mask;
-        self.disk.write_block(0, &super_block)?;
+    pub fn read_inode(&self, ino: usize, inode: &mut [u8]) -> Result<()> {
+        self.check_inode(ino)?;
+        let (block_num, offset) = self.inode_location(ino);
+        let mut block = vec![0u8; self.block_size()];
+        self.disk.read_block(block_num, &mut block)?;
+        inode.copy_from_slice(&block[offset..offset + INODE_SIZE]);
         Ok(())
     }
 
-    pub fn read_block(&self, block_num: usize, block: &mut Vec<u8>) -> Result<()> {
+    pub fn write_inode(&self, ino: usize, inode: &[u8]) -> Result<()> {
+        self.check_inode(ino)?;
+        if inode.len() > INODE_SIZE {
+            return Err(FSError::BlockError("Buffer exceeds inode size".to_owned()));
+        }
+        let (block_num, offset) = self.inode_location(ino);
+        let mut block = vec![0u8; self.block_size()];
+        self.disk.read_block(block_num, &mut block)?;
+        block[offset..offset + INODE_SIZE].fill(0);
+        block[offset..offset + inode.len()].copy_from_slice(inode);
+        self.disk.write_block(block_num, &block)
+    }
+
+    pub fn read_block(&self, block_num: usize, block: &mut [u8]) -> Result<()> {
         self.disk.read_block(block_num, block)
     }
 
-    pub fn write_block(&self, block_num: usize, block: &Vec<u8>) -> Result<()> {
+    pub fn write_block(&self, block_num: usize, block: &[u8]) -> Result<()> {
         self.disk.write_block(block_num, block)
     }
 }
 
+/// Sets or clears `len` bits of `bitmap` from bit `start`.
+fn mark_bits(bitmap: &mut [u8], start: usize, len: usize, used: bool) {
+    for bit in start..start + len {
+        let mask = 1 << (bit % 8);
+        match used {
+            true => bitmap[bit / 8] |= mask,
+            false => bitmap[bit / 8] &= !mask,
+        }
+    }
+}
+
 #[cfg(test)]
 mod test {
+    use std::path::PathBuf;
+    use crate::virtual_file_sys::disk::MemDisk;
     use super::*;
+
+    fn temp_image(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("sarari-{}-{}", name, std::process::id()))
+    }
+
     #[test]
     fn test() -> Result<()> {
         Ok(())
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_mount_keeps_data() -> Result<()> {
+        let path = temp_image("fs");
+        let fs = FileSys::format(&path, &FormatOptions::default())?;
+        fs.mkdir("dir000001")?;
+        fs.create("file00001")?;
+        fs.unmount()?;
+
+        let fs = FileSys::mount(&path)?;
+        assert_eq!(fs.ls(".")?, "dir000001/ file00001");
+        fs.unmount()?;
+        std::fs::remove_file(path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_mem_disk() -> Result<()> {
+        let disk = MemDisk::new(512, 64);
+        let fs = FileSys::format_device(Box::new(disk.clone()), "memory")?;
+        fs.create("file00001")?;
+        fs.unmount()?;
+
+        let fs = FileSys::mount_device(Box::new(disk.clone()))?;
+        assert_eq!(fs.ls(".")?, "file00001");
+        fs.unmount()?;
+
+        assert!(matches!(FileSys::mount_device(Box::new(MemDisk::new(512, 64))),
+            Err(FSError::NotFormattedError)));
+        Ok(())
+    }
+
+    #[test]
+    fn test_super_block() -> Result<()> {
+        let path = temp_image("sb");
+        let options = FormatOptions { label: "volume".to_owned(), ..FormatOptions::default() };
+        let bfs = BasicFileSys::format(&path, &options)?;
+        let uuid = bfs.get_super_block().get_uuid();
+        assert_eq!(bfs.get_super_block().get_label(), "volume");
+        assert_eq!(bfs.get_super_block().get_mounted(), 0);
+        bfs.unmount()?;
+
+        let bfs = BasicFileSys::mount(&path)?;
+        assert_eq!(bfs.get_super_block().get_uuid(), uuid);
+        assert!(bfs.get_super_block().get_mounted() >= bfs.get_super_block().get_created());
+        let mut block = vec![0u8; bfs.block_size()];
+        bfs.read_block(SUPER_BLOCK_ID, &mut block)?;
+        // Bump the format version past the one this build understands.
+        block[4] = 0xFF;
+        bfs.write_block(SUPER_BLOCK_ID, &block)?;
+        bfs.unmount()?;
+
+        assert!(matches!(BasicFileSys::mount(&path), Err(FSError::UnsupportedFormatError(_))));
+        let options = FormatOptions { label: "a-label-that-is-too-long".to_owned(), ..FormatOptions::default() };
+        assert!(matches!(BasicFileSys::format(&path, &options), Err(FSError::LabelTooLongError)));
+        std::fs::remove_file(path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_geometry() -> Result<()> {
+        let path = temp_image("geometry");
+        // Two bitmap blocks are needed to cover 5000 blocks of 512 bytes,
+        // and 64 inodes of 256 bytes fill 32 blocks of inode table.
+        let options = FormatOptions { block_size: 512, num_blocks: 5000, num_inodes: Some(64), ..FormatOptions::default() };
+        let bfs = BasicFileSys::format(&path, &options)?;
+        assert_eq!(bfs.get_super_block().get_bitmap_blocks(), 2);
+        assert_eq!(bfs.get_super_block().get_inode_bitmap_start(), 3);
+        assert_eq!(bfs.get_super_block().get_inode_table_start(), 4);
+        assert_eq!(bfs.get_super_block().get_first_data_block(), 36);
+        assert_eq!(std::fs::metadata(&path)?.len(), 5000 * 512);
+        bfs.unmount()?;
+
+        let mut bfs = BasicFileSys::mount(&path)?;
+        assert_eq!(bfs.block_size(), 512);
+        assert_eq!(bfs.num_blocks(), 5000);
+        let mut allocated = vec![false; 5000];
+        while let Some(id) = bfs.get_free_block()? {
+            assert!(!allocated[id]);
+            allocated[id] = true;
+        }
+        // Everything but the metadata and the root directory block.
+        assert_eq!(allocated.iter().filter(|&&used| used).count(), 5000 - 37);
+        bfs.reclaim_block(4999)?;
+        assert_eq!(bfs.get_free_block()?, Some(4999));
+        bfs.unmount()?;
+
+        let options = FormatOptions { block_size: 1000, ..FormatOptions::default() };
+        assert!(matches!(BasicFileSys::format(&path, &options), Err(FSError::InvalidGeometryError(_))));
+        let options = FormatOptions { num_blocks: 3, ..FormatOptions::default() };
+        assert!(matches!(BasicFileSys::format(&path, &options), Err(FSError::InvalidGeometryError(_))));
+        let options = FormatOptions { num_inodes: Some(1), ..FormatOptions::default() };
+        assert!(matches!(BasicFileSys::format(&path, &options), Err(FSError::InvalidGeometryError(_))));
+        std::fs::remove_file(path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_allocate_extent() -> Result<()> {
+        let mut bfs = BasicFileSys::format_device(Box::new(MemDisk::new(512, 64)), "")?;
+        let first = bfs.get_super_block().get_first_data_block() + 1;
+        assert_eq!(bfs.allocate_extent(None, 8)?, Some((first, 8)));
+        // A free goal block continues the run there.
+        assert_eq!(bfs.allocate_extent(Some(first + 8), 4)?, Some((first + 8, 4)));
+
+        // With only scattered free blocks left the longest run is handed out.
+        while bfs.get_free_block()?.is_some() {}
+        bfs.reclaim_extent(first + 1, 2)?;
+        bfs.reclaim_extent(first + 5, 3)?;
+        assert_eq!(bfs.allocate_extent(Some(first), 4)?, Some((first + 5, 3)));
+        assert_eq!(bfs.allocate_extent(None, 4)?, Some((first + 1, 2)));
+        assert_eq!(bfs.allocate_extent(None, 1)?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_wide_block_pointers() -> Result<()> {
+        let fs = FileSys::format_device(Box::new(MemDisk::new(512, 1024)), "")?;
+        // Push the next free block well past what a one-byte pointer could address.
+        for _ in 0..300 {
+            fs.wrapped_file_sys.file_sys().get_free_block()?;
+        }
+        fs.create("file00001")?;
+        fs.append("file00001", b"hello")?;
+        assert_eq!(fs.cat("file00001")?, b"hello");
+        Ok(())
+    }
+
+    #[test]
+    fn test_large_file() -> Result<()> {
+        let disk = MemDisk::new(512, 1024);
+        let fs = FileSys::format_device(Box::new(disk.clone()), "")?;
+        let data: Vec<u8> = (0..300 * 512).map(|i| b'a' + (i % 26) as u8).collect();
+        fs.create("file00001")?;
+        fs.append("file00001", &data)?;
+        assert_eq!(fs.cat("file00001")?, data);
+        fs.unmount()?;
+
+        let fs = FileSys::mount_device(Box::new(disk))?;
+        assert_eq!(fs.cat("file00001")?, data);
+        Ok(())
+    }
+
+    #[test]
+    fn test_binary_contents() -> Result<()> {
+        let disk = MemDisk::new(512, 64);
+        let fs = FileSys::format_device(Box::new(disk.clone()), "")?;
+        // Not valid UTF-8, and not a whole number of blocks.
+        let data: Vec<u8> = (0..=255u8).rev().cycle().take(3 * 512 + 7).collect();
+        fs.create("image.bin")?;
+        fs.append("image.bin", &data)?;
+        assert_eq!(fs.cat("image.bin")?, data);
+        fs.create("empty")?;
+        assert_eq!(fs.cat("empty")?, b"");
+        fs.unmount()?;
+
+        let fs = FileSys::mount_device(Box::new(disk))?;
+        assert_eq!(fs.cat("image.bin")?, data);
+        Ok(())
+    }
+
+    #[test]
+    fn test_append_sizes() -> Result<()> {
+        let disk = MemDisk::new(512, 256);
+        let fs = FileSys::format_device(Box::new(disk.clone()), "")?;
+        let free_blocks = fs.wrapped_file_sys.file_sys().count_free_blocks()?;
+        fs.create("file")?;
+        // Appends ending inside, exactly on, and past a block boundary, some of
+        // them spanning several blocks.
+        let mut expected = Vec::new();
+        for (i, len) in [300, 100, 112, 1, 511, 1030, 3, 509, 2000].into_iter().enumerate() {
+            let data: Vec<u8> = (0..len).map(|k| (i * 31 + k) as u8).collect();
+            fs.append("file", &data)?;
+            expected.extend_from_slice(&data);
+            let file = fs.open_file("file")?;
+            assert_eq!(file.get_size(), expected.len());
+            assert_eq!(fs.stat("file")?.blocks, expected.len().div_ceil(512));
+            assert_eq!(fs.cat("file")?, expected);
+        }
+        assert_eq!(fs.wrapped_file_sys.file_sys().count_free_blocks()?, free_blocks - expected.len().div_ceil(512));
+        fs.unmount()?;
+
+        let fs = FileSys::mount_device(Box::new(disk))?;
+        let mut file = fs.open_file("file")?;
+        assert_eq!(file.get_size(), expected.len());
+        assert_eq!(fs.cat("file")?, expected);
+        // The size is kept in bytes, both in memory and on disk.
+        file.set_size(&fs.wrapped_file_sys, 1000)?;
+        assert_eq!(file.get_size(), 1000);
+        assert_eq!(fs.open_file("file")?.get_size(), 1000);
+        fs.append("file", b"end")?;
+        assert_eq!(fs.read_at("file", 995, 100)?, [&expected[995..1000], b"end"].concat());
+        Ok(())
+    }
+
+    #[test]
+    fn test_append_disk_full() -> Result<()> {
+        let disk = MemDisk::new(512, 64);
+        let fs = FileSys::format_device(Box::new(disk.clone()), "")?;
+        fs.create("file")?;
+        fs.create("filler")?;
+        let mut expected = vec![b'a'; 700];
+        fs.append("file", &expected)?;
+        while fs.wrapped_file_sys.file_sys().count_free_blocks()? > 0 {
+            fs.append("filler", &[b'f'; 512])?;
+        }
+
+        // Filling the tail of the last block takes no new block.
+        fs.append("file", &[b'b'; 300])?;
+        expected.extend_from_slice(&[b'b'; 300]);
+        // Running out of space part way leaves the file as it was, tail included.
+        assert!(matches!(fs.append("file", &[b'c'; 100]), Err(FSError::DiskFullError)));
+        assert_eq!(fs.cat("file")?, expected);
+        assert_eq!(fs.wrapped_file_sys.file_sys().count_free_blocks()?, 0);
+        fs.unmount()?;
+
+        let fs = FileSys::mount_device(Box::new(disk))?;
+        assert_eq!(fs.cat("file")?, expected);
+        fs.truncate("filler", 0)?;
+        fs.append("file", &[b'c'; 100])?;
+        expected.extend_from_slice(&[b'c'; 100]);
+        assert_eq!(fs.cat("file")?, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn test_new_entry_disk_full() -> Result<()> {
+        let disk = MemDisk::new(512, 128);
+        let options = FormatOptions { block_size: 512, num_blocks: 128, num_inodes: Some(64), ..FormatOptions::default() };
+        let fs = FileSys::open(BasicFileSys::format_with(Box::new(disk), &options)?)?;
+        fs.create("filler")?;
+        while fs.wrapped_file_sys.file_sys().count_free_blocks()? > 0 {
+            fs.append("filler", &[b'f'; 512])?;
+        }
+        let mut free_inodes = fs.wrapped_file_sys.file_sys().count_free_inodes()?;
+        let mut i = 0;
+        while fs.create(&format!("file{i:03}")).is_ok() {
+            i += 1;
+            free_inodes -= 1;
+        }
+        assert!(free_inodes > 0);
+
+        // The directory has no room left and cannot grow, so the new inode goes back.
+        assert!(matches!(fs.create("file"), Err(FSError::DiskFullError)));
+        assert_eq!(fs.wrapped_file_sys.file_sys().count_free_inodes()?, free_inodes);
+        // With one block free, the new directory gets its block but its parent still
+        // cannot grow; both the block and the inode go back.
+        let size = fs.stat("filler")?.size;
+        fs.truncate("filler", size - 512)?;
+        assert!(matches!(fs.mkdir("dir"), Err(FSError::DiskFullError)));
+        assert_eq!(fs.wrapped_file_sys.file_sys().count_free_inodes()?, free_inodes);
+        assert_eq!(fs.wrapped_file_sys.file_sys().count_free_blocks()?, 1);
+        assert!(!fs.ls(".")?.contains("dir/"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_rmdir_working_dir() -> Result<()> {
+        let disk = MemDisk::new(512, 256);
+        let mut fs = FileSys::format_device(Box::new(disk), "")?;
+        let free_inodes = fs.wrapped_file_sys.file_sys().count_free_inodes()?;
+        fs.mkdir("a")?;
+        fs.cd("a")?;
+        fs.rmdir("/a")?;
+        assert_eq!(fs.ls(".")?, "");
+        fs.cd("/")?;
+        assert_eq!(fs.ls("/")?, "");
+        // The freed inode going to a new file leaves the working directory alone.
+        fs.create("file")?;
+        assert_eq!(fs.ls(".")?, "file");
+        fs.rm("file")?;
+        assert_eq!(fs.wrapped_file_sys.file_sys().count_free_inodes()?, free_inodes);
+        Ok(())
+    }
+
+    #[test]
+    fn test_rename_over_working_dir() -> Result<()> {
+        let disk = MemDisk::new(512, 256);
+        let mut fs = FileSys::format_device(Box::new(disk), "")?;
+        fs.mkdir("a")?;
+        fs.mkdir("b")?;
+        fs.cd("b")?;
+        fs.rename("/a", "/b")?;
+        assert_eq!(fs.ls(".")?, "b/");
+        fs.cd("/")?;
+        assert_eq!(fs.ls("/")?, "b/");
+        fs.cd("b")?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_rm_frees_space() -> Result<()> {
+        let disk = MemDisk::new(512, 1024);
+        let mut fs = FileSys::format_device(Box::new(disk.clone()), "")?;
+        let free_blocks = fs.wrapped_file_sys.file_sys().count_free_blocks()?;
+        let free_inodes = fs.wrapped_file_sys.file_sys().count_free_inodes()?;
+        fs.create("inline")?;
+        fs.append("inline", b"small")?;
+        fs.create("large")?;
+        fs.append("large", &vec![b'l'; 20 * 512])?;
+        // Every other block written leaves holes, so the file needs an extent block.
+        fs.create("sparse")?;
+        for i in 0..40 {
+            fs.write_at("sparse", i * 1024, b"s")?;
+        }
+        fs.mkdir("dir")?;
+        for i in 0..100 {
+            fs.create(&format!("dir/file{i:03}"))?;
+        }
+        assert!(fs.wrapped_file_sys.file_sys().count_free_blocks()? < free_blocks - 20 - 40);
+
+        // A directory that still holds entries is left alone, entries and all.
+        fs.mkdir("kept")?;
+        fs.create("kept/file")?;
+        fs.append("kept/file", &[b'k'; 1000])?;
+        let blocks_in_use = fs.wrapped_file_sys.file_sys().count_free_blocks()?;
+        let inodes_in_use = fs.wrapped_file_sys.file_sys().count_free_inodes()?;
+        assert!(matches!(fs.rmdir("kept"), Err(FSError::DirNotEmptyError)));
+        assert_eq!(fs.wrapped_file_sys.file_sys().count_free_blocks()?, blocks_in_use);
+        assert_eq!(fs.wrapped_file_sys.file_sys().count_free_inodes()?, inodes_in_use);
+        assert_eq!(fs.cat("kept/file")?, vec![b'k'; 1000]);
+        fs.rm("kept/file")?;
+        fs.rmdir("kept")?;
+
+        for i in 0..100 {
+            fs.rm(&format!("dir/file{i:03}"))?;
+        }
+        // Only directories go through rmdir, not even links to one.
+        fs.symlink("dir", "link")?;
+        assert!(matches!(fs.rmdir("inline"), Err(FSError::NotDirError)));
+        assert!(matches!(fs.rmdir("link"), Err(FSError::NotDirError)));
+        assert!(matches!(fs.rmdir("missing"), Err(FSError::FileNotFoundError)));
+        assert_eq!(fs.cat("inline")?, b"small");
+        fs.rm("link")?;
+        fs.rmdir("dir")?;
+        fs.rm("inline")?;
+        fs.rm("large")?;
+        fs.rm("sparse")?;
+        assert_eq!(fs.ls(".")?, "");
+        assert_eq!(fs.wrapped_file_sys.file_sys().count_free_blocks()?, free_blocks);
+        assert_eq!(fs.wrapped_file_sys.file_sys().count_free_inodes()?, free_inodes);
+
+        // Replacing a file by renaming another over it frees the replaced one.
+        fs.create("a")?;
+        fs.append("a", &[b'a'; 1000])?;
+        fs.create("b")?;
+        fs.append("b", &[b'b'; 3000])?;
+        fs.rename("a", "b")?;
+        assert_eq!(fs.cat("b")?, vec![b'a'; 1000]);
+        assert_eq!(fs.wrapped_file_sys.file_sys().count_free_blocks()?, free_blocks - 2);
+        assert_eq!(fs.wrapped_file_sys.file_sys().count_free_inodes()?, free_inodes - 1);
+        fs.unmount()?;
+
+        let mut fs = FileSys::mount_device(Box::new(disk))?;
+        fs.rm("b")?;
+        assert_eq!(fs.wrapped_file_sys.file_sys().count_free_blocks()?, free_blocks);
+        assert_eq!(fs.wrapped_file_sys.file_sys().count_free_inodes()?, free_inodes);
+        Ok(())
+    }
+
+    #[test]
+    fn test_on_disk_layout() -> Result<()> {
+        let fs = FileSys::format_device(Box::new(MemDisk::new(512, 64)), "layout")?;
+        fs.create("file00001")?;
+        let bfs = fs.wrapped_file_sys.file_sys();
+        let mut block = vec![0u8; 512];
+        bfs.read_block(SUPER_BLOCK_ID, &mut block)?;
+        assert_eq!(&block[0..4], &SUPER_BLOCK_MAGIC.to_le_bytes());
+        assert_eq!(&block[4..8], &FORMAT_VERSION.to_le_bytes());
+        assert_eq!(&block[8..12], &512u32.to_le_bytes());
+        assert_eq!(&block[56..62], b"layout");
+
+        let root_dir_id = bfs.get_root_dir_id();
+        assert_eq!(root_dir_id, ROOT_INO);
+        let mut inode = [0u8; INODE_SIZE];
+        bfs.read_inode(root_dir_id, &mut inode)?;
+        assert_eq!(&inode[0..4], b"SRIN");
+        assert_eq!(InodeType::of_inode(&inode), Some(InodeType::Dir));
+        assert_eq!(get_u64(&inode, 8), 512);
+        // The first inline extent maps the directory block.
+        let dir_block = get_u32(&inode, INODE_BLOCK_OFFSET + 4) as usize;
+        assert_eq!(dir_block, bfs.get_super_block().get_first_data_block());
+
+        bfs.read_block(dir_block, &mut block)?;
+        let file_id = get_u32(&block, 0) as usize;
+        assert_eq!(file_id, ROOT_INO + 1);
+        // A lone record stretches to the end of the block.
+        assert_eq!(get_u16(&block, 4), 512);
+        assert_eq!(block[6], 9);
+        assert_eq!(block[7], InodeType::File as u8);
+        assert_eq!(&block[8..17], b"file00001");
+
+        bfs.read_inode(file_id, &mut inode)?;
+        assert_eq!(InodeType::of_inode(&inode), Some(InodeType::File));
+        assert_eq!(get_u64(&inode, 8), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_inode_table() -> Result<()> {
+        let disk = MemDisk::new(512, 64);
+        let fs = FileSys::format_device(Box::new(disk.clone()), "")?;
+        let free_blocks = fs.wrapped_file_sys.file_sys().count_free_blocks()?;
+        // 16 inodes by default on a disk this small, less the unused inode 0 and the root directory.
+        assert_eq!(fs.wrapped_file_sys.file_sys().count_free_inodes()?, 14);
+        for i in 0..14 {
+            fs.create(&format!("file{i:05}"))?;
+        }
+        // Empty files take an inode each but no blocks.
+        assert_eq!(fs.wrapped_file_sys.file_sys().count_free_blocks()?, free_blocks);
+        assert_eq!(fs.wrapped_file_sys.file_sys().count_free_inodes()?, 0);
+        assert!(matches!(fs.create("file00014"), Err(FSError::DiskFullError)));
+        fs.unmount()?;
+
+        let fs = FileSys::mount_device(Box::new(disk))?;
+        assert_eq!(fs.wrapped_file_sys.file_sys().count_free_inodes()?, 0);
+        assert_eq!(fs.ls(".")?.split(' ').count(), 14);
+        Ok(())
+    }
+
+    #[test]
+    fn test_inline_data() -> Result<()> {
+        let disk = MemDisk::new(512, 64);
+        let fs = FileSys::format_device(Box::new(disk.clone()), "")?;
+        assert!(fs.wrapped_file_sys.file_sys().get_super_block().has_feature_incompat(FEATURE_INCOMPAT_INLINE_DATA));
+        let free_blocks = fs.wrapped_file_sys.file_sys().count_free_blocks()?;
+        fs.create("file00001")?;
+        fs.append("file00001", b"key=value\n")?;
+        let data = [b'x'; INLINE_DATA_SIZE - 10];
+        fs.append("file00001", &data)?;
+        // Still held in the inode.
+        assert_eq!(fs.wrapped_file_sys.file_sys().count_free_blocks()?, free_blocks);
+        fs.unmount()?;
+
+        let fs = FileSys::mount_device(Box::new(disk))?;
+        let content = fs.cat("file00001")?;
+        assert_eq!(content.len(), INLINE_DATA_SIZE);
+        assert!(content.starts_with(b"key=value\nxxx"));
+
+        // One more byte moves the contents out to a data block.
+        fs.append("file00001", b"!")?;
+        assert_eq!(fs.wrapped_file_sys.file_sys().count_free_blocks()?, free_blocks - 1);
+        let content = fs.cat("file00001")?;
+        assert!(content.starts_with(b"key=value\nxxx"));
+        assert_eq!(content.len(), INLINE_DATA_SIZE + 1);
+        assert_eq!(content.last(), Some(&b'!'));
+        Ok(())
+    }
+
+    #[test]
+    fn test_long_names() -> Result<()> {
+        let disk = MemDisk::new(512, 64);
+        let fs = FileSys::format_device(Box::new(disk.clone()), "")?;
+        let long_name = format!("{}.txt", "é".repeat(125));
+        assert_eq!(long_name.len(), 254);
+        fs.create(&long_name)?;
+        fs.mkdir("a dir with spaces")?;
+        assert!(matches!(fs.create(&"x".repeat(256)), Err(FSError::FileNameTooLongError)));
+        for name in ["", ".", "..", "/", "a dir with spaces/..", "nul\0"] {
+            assert!(matches!(fs.create(name), Err(FSError::InvalidFileNameError)));
+        }
+        fs.unmount()?;
+
+        let mut fs = FileSys::mount_device(Box::new(disk))?;
+        assert_eq!(fs.ls(".")?, format!("a dir with spaces/ {long_name}"));
+        fs.rm(&long_name)?;
+        fs.create("short")?;
+        fs.cd("a dir with spaces")?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_paths() -> Result<()> {
+        let disk = MemDisk::new(512, 256);
+        let mut fs = FileSys::format_device(Box::new(disk.clone()), "")?;
+        fs.mkdir("a")?;
+        fs.mkdir("a/b")?;
+        fs.mkdir("/a/b/c/")?;
+        fs.create("a/b/file")?;
+        fs.append("/a/b/file", b"hello")?;
+        assert_eq!(fs.ls("a//b")?, "c/ file");
+        assert!(matches!(fs.create("missing/file"), Err(FSError::FileNotFoundError)));
+        assert!(matches!(fs.create("a/b/file/x"), Err(FSError::NotDirError)));
+        assert!(matches!(fs.cd("a/b/file"), Err(FSError::NotDirError)));
+
+        fs.cd("a/./b/c")?;
+        assert_eq!(fs.cat("../file")?, b"hello");
+        assert_eq!(fs.cat("/a/b/file")?, b"hello");
+        fs.cd("../..")?;
+        assert_eq!(fs.ls(".")?, "b/");
+        // The root is its own parent.
+        fs.cd("/../..")?;
+        assert_eq!(fs.ls(".")?, "a/");
+        fs.rm("a/b/file")?;
+        fs.rmdir("a/b/c")?;
+        assert_eq!(fs.ls("/a/b")?, "");
+        fs.unmount()?;
+
+        // Parents are kept on disk.
+        let mut fs = FileSys::mount_device(Box::new(disk))?;
+        fs.cd("/a/b")?;
+        fs.cd("..")?;
+        assert_eq!(fs.ls(".")?, "b/");
+
+        // Absolute paths never read the working directory.
+        fs.cd("b")?;
+        fs.wrapped_file_sys.file_sys().write_inode(fs.curr_dir, &[0u8; INODE_SIZE])?;
+        assert!(fs.ls(".").is_err());
+        assert_eq!(fs.ls("/a")?, "b/");
+        fs.cd("/")?;
+        assert_eq!(fs.ls(".")?, "a/");
+        Ok(())
+    }
+
+    #[test]
+    fn test_rename() -> Result<()> {
+        let disk = MemDisk::new(512, 256);
+        let mut fs = FileSys::format_device(Box::new(disk.clone()), "")?;
+        fs.mkdir("a")?;
+        fs.mkdir("a/b")?;
+        fs.mkdir("c")?;
+        fs.create("a/file")?;
+        fs.append("a/file", b"first")?;
+        fs.create("c/other")?;
+        fs.append("c/other", b"second")?;
+
+        // Within a directory, then across directories replacing a file.
+        fs.rename("a/file", "a/renamed")?;
+        assert_eq!(fs.ls("a")?, "b/ renamed");
+        fs.rename("a/renamed", "c/other")?;
+        assert_eq!(fs.ls("a")?, "b/");
+        assert_eq!(fs.ls("c")?, "other");
+        assert_eq!(fs.cat("c/other")?, b"first");
+
+        // Moving a directory takes its subtree along and updates its parent.
+        fs.rename("a/b", "c/b")?;
+        fs.cd("/c/b")?;
+        fs.cd("..")?;
+        assert_eq!(fs.ls(".")?, "b/ other");
+        fs.home()?;
+        assert!(matches!(fs.rename("c", "c/b/c"), Err(FSError::InvalidMoveError)));
+        assert!(matches!(fs.rename("c", "c/x"), Err(FSError::InvalidMoveError)));
+        assert!(matches!(fs.rename("c/other", "a"), Err(FSError::NotAFileError)));
+        assert!(matches!(fs.rename("a", "c/other"), Err(FSError::NotDirError)));
+        assert!(matches!(fs.rename("c", "a/../c"), Ok(())));
+        assert!(matches!(fs.rename("missing", "a/x"), Err(FSError::FileNotFoundError)));
+        // An empty directory is replaced, a non-empty one is not.
+        assert!(matches!(fs.rename("a", "c"), Err(FSError::DirNotEmptyError)));
+        fs.rename("c", "a")?;
+        assert_eq!(fs.ls("/")?, "a/");
+        fs.unmount()?;
+
+        let mut fs = FileSys::mount_device(Box::new(disk))?;
+        fs.cd("a/b")?;
+        fs.cd("../..")?;
+        assert_eq!(fs.ls("a")?, "b/ other");
+        Ok(())
+    }
+
+    #[test]
+    fn test_rm_r() -> Result<()> {
+        let disk = MemDisk::new(512, 1024);
+        let mut fs = FileSys::format_device(Box::new(disk.clone()), "")?;
+        let free_blocks = fs.wrapped_file_sys.file_sys().count_free_blocks()?;
+        let free_inodes = fs.wrapped_file_sys.file_sys().count_free_inodes()?;
+        fs.mkdir("build")?;
+        fs.mkdir("build/obj")?;
+        fs.mkdir("build/obj/deep")?;
+        fs.create("build/out")?;
+        fs.append("build/out", &[b'o'; 2000])?;
+        for i in 0..50 {
+            fs.create(&format!("build/obj/file{i:02}"))?;
+            fs.append(&format!("build/obj/file{i:02}"), &[i as u8; 600])?;
+        }
+        fs.create("build/obj/deep/last")?;
+
+        // A non-empty directory needs rm_r.
+        assert!(matches!(fs.rmdir("build/obj/deep"), Err(FSError::DirNotEmptyError)));
+        fs.rm("build/obj/deep/last")?;
+        fs.rmdir("build/obj/deep")?;
+        fs.mkdir("build/obj/deep")?;
+
+        fs.cd("build/obj/deep")?;
+        assert_eq!(fs.rm_r("/build")?, 54);
+        // The working directory went with the tree.
+        assert_eq!(fs.ls(".")?, "");
+        assert!(matches!(fs.cd("/build"), Err(FSError::FileNotFoundError)));
+        assert_eq!(fs.wrapped_file_sys.file_sys().count_free_blocks()?, free_blocks);
+        assert_eq!(fs.wrapped_file_sys.file_sys().count_free_inodes()?, free_inodes);
+        assert!(matches!(fs.rm_r("build"), Err(FSError::FileNotFoundError)));
+        assert!(matches!(fs.rm_r("/"), Err(FSError::InvalidFileNameError)));
+
+        // A failure part way says how far it got and leaves the rest in place.
+        fs.mkdir("tree")?;
+        fs.mkdir("tree/sub")?;
+        fs.create("tree/sub/a")?;
+        fs.create("tree/sub/b")?;
+        let broken = fs.resolve_dir("tree/sub")?.lookup(&fs.wrapped_file_sys, "b")?.unwrap();
+        fs.wrapped_file_sys.file_sys().write_inode(broken.get_id(), &[0u8; INODE_SIZE])?;
+        assert!(matches!(fs.rm_r("tree"), Err(FSError::PartialRemoveError { removed: 1, .. })));
+        assert_eq!(fs.ls("tree/sub")?, "b");
+        fs.unmount()?;
+
+        let fs = FileSys::mount_device(Box::new(disk))?;
+        assert_eq!(fs.ls("/")?, "tree/");
+        Ok(())
+    }
+
+    #[test]
+    fn test_metadata() -> Result<()> {
+        let disk = MemDisk::new(512, 256);
+        let mut fs = FileSys::format_device(Box::new(disk.clone()), "")?;
+        let start = now();
+        fs.create("file")?;
+        let stat = fs.stat("file")?;
+        assert_eq!((stat.kind, stat.mode, stat.links, stat.uid, stat.gid), (InodeType::File, 0o644, 1, 0, 0));
+        assert_eq!(stat.mode_string(), "-rw-r--r--");
+        assert!(stat.atime >= start && stat.mtime >= start && stat.ctime >= start);
+
+        // Writing moves mtime and ctime, reading moves atime.
+        fs.set_times("file", 1, 2)?;
+        fs.append("file", &[b'x'; 700])?;
+        let stat = fs.stat("file")?;
+        assert_eq!((stat.size, stat.blocks, stat.atime), (700, 2, 1));
+        assert!(stat.mtime >= start && stat.ctime >= start);
+        fs.set_times("file", 1, 2)?;
+        fs.cat("file")?;
+        let stat = fs.stat("file")?;
+        assert_eq!(stat.mtime, 2);
+        assert!(stat.atime >= start);
+
+        fs.chmod("file", 0o100600)?;
+        fs.chown("file", 1000, 100)?;
+        let stat = fs.stat("file")?;
+        assert_eq!((stat.mode, stat.uid, stat.gid), (0o600, 1000, 100));
+
+        // Subdirectories link to their parent; adding an entry is a change of the directory.
+        fs.mkdir("a")?;
+        fs.mkdir("a/b")?;
+        fs.mkdir("a/c")?;
+        fs.set_times("a", 0, 0)?;
+        fs.create("a/f")?;
+        let stat = fs.stat("a/")?;
+        assert_eq!((stat.kind, stat.mode_string().as_str(), stat.links), (InodeType::Dir, "drwxr-xr-x", 4));
+        assert!(stat.mtime >= start);
+        assert_eq!(fs.stat("/")?.links, 3);
+        fs.rmdir("a/c")?;
+        fs.rename("a/b", "b")?;
+        assert_eq!(fs.stat("a")?.links, 2);
+        assert_eq!(fs.stat(".")?.links, 4);
+        assert_eq!(fs.stat("a/..")?.ino, fs.stat("/")?.ino);
+        assert!(matches!(fs.stat("a/missing"), Err(FSError::FileNotFoundError)));
+
+        fs.set_times("a/f", 0, 1709296200)?;
+        assert_eq!(fs.ls_long("a")?, "-rw-r--r--   1     0     0          0 2024-03-01 12:30:00 f");
+        let stat = fs.stat("file")?;
+        fs.unmount()?;
+
+        let fs = FileSys::mount_device(Box::new(disk))?;
+        assert_eq!(fs.stat("file")?, stat);
+        assert_eq!(fs.stat("/")?.links, 4);
+        Ok(())
+    }
+
+    #[test]
+    fn test_hard_links() -> Result<()> {
+        let disk = MemDisk::new(512, 256);
+        let mut fs = FileSys::format_device(Box::new(disk.clone()), "")?;
+        let free_blocks = fs.wrapped_file_sys.file_sys().count_free_blocks()?;
+        let free_inodes = fs.wrapped_file_sys.file_sys().count_free_inodes()?;
+        fs.mkdir("store")?;
+        fs.create("out")?;
+        fs.append("out", &[b'o'; 1500])?;
+        fs.link("out", "store/blob")?;
+        fs.link("store/blob", "copy")?;
+        assert_eq!(fs.stat("out")?.links, 3);
+        assert_eq!(fs.stat("out")?.ino, fs.stat("store/blob")?.ino);
+        assert_eq!(fs.wrapped_file_sys.file_sys().count_free_inodes()?, free_inodes - 2);
+
+        // Every name leads to the same contents.
+        fs.append("copy", b"!")?;
+        assert_eq!(fs.stat("store/blob")?.size, 1501);
+        assert!(matches!(fs.link("out", "copy"), Err(FSError::FileExistsError)));
+        assert!(matches!(fs.link("store", "dir"), Err(FSError::NotAFileError)));
+        assert!(matches!(fs.link("missing", "other"), Err(FSError::FileNotFoundError)));
+        fs.rename("copy", "out")?;
+        assert_eq!(fs.stat("out")?.links, 3);
+        fs.unmount()?;
+
+        // The blocks stay until the last name goes.
+        let mut fs = FileSys::mount_device(Box::new(disk))?;
+        let used = free_blocks - fs.wrapped_file_sys.file_sys().count_free_blocks()?;
+        fs.rm("out")?;
+        fs.rm("copy")?;
+        assert_eq!(fs.stat("store/blob")?.links, 1);
+        assert_eq!(fs.cat("store/blob")?.len(), 1501);
+        assert_eq!(free_blocks - fs.wrapped_file_sys.file_sys().count_free_blocks()?, used);
+        fs.create("other")?;
+        fs.link("other", "store/other")?;
+        fs.rename("store/blob", "store/other")?;
+        assert_eq!(fs.stat("other")?.links, 1);
+        fs.rm_r("store")?;
+        fs.rm("other")?;
+        assert_eq!(fs.wrapped_file_sys.file_sys().count_free_blocks()?, free_blocks);
+        assert_eq!(fs.wrapped_file_sys.file_sys().count_free_inodes()?, free_inodes);
+        Ok(())
+    }
+
+    #[test]
+    fn test_symlinks() -> Result<()> {
+        let disk = MemDisk::new(512, 256);
+        let mut fs = FileSys::format_device(Box::new(disk.clone()), "")?;
+        let free_blocks = fs.wrapped_file_sys.file_sys().count_free_blocks()?;
+        let free_inodes = fs.wrapped_file_sys.file_sys().count_free_inodes()?;
+        fs.mkdir("releases")?;
+        fs.mkdir("releases/v1")?;
+        fs.create("releases/v1/app")?;
+        fs.append("releases/v1/app", b"v1")?;
+        fs.symlink("releases/v1", "current")?;
+        fs.symlink("v1/app", "releases/app")?;
+        fs.symlink("/releases/v1/app", "releases/v1/self")?;
+        assert_eq!(fs.cat("current/app")?, b"v1");
+        assert_eq!(fs.cat("releases/app")?, b"v1");
+        assert_eq!(fs.cat("current/self")?, b"v1");
+        assert_eq!(fs.readlink("current")?, "releases/v1");
+        assert_eq!(fs.stat("current")?.kind, InodeType::Dir);
+        assert_eq!(fs.lstat("current")?.kind, InodeType::Symlink);
+        assert_eq!(fs.lstat("current")?.size, "releases/v1".len());
+        assert_eq!(fs.ls(".")?, "releases/ current@");
+        assert!(fs.ls_long(".")?.contains("lrwxrwxrwx"));
+        assert!(fs.ls_long(".")?.ends_with("current -> releases/v1"));
+        assert!(matches!(fs.readlink("releases/v1/app"), Err(FSError::NotSymlinkError)));
+        assert!(matches!(fs.symlink("x", "current"), Err(FSError::FileExistsError)));
+        assert!(matches!(fs.symlink("", "empty"), Err(FSError::InvalidFileNameError)));
+
+        // Dangling links and loops only fail once followed.
+        fs.symlink("missing", "dangling")?;
+        assert!(matches!(fs.cat("dangling"), Err(FSError::FileNotFoundError)));
+        fs.symlink("loop2", "loop1")?;
+        fs.symlink("loop1/x", "loop2")?;
+        assert!(matches!(fs.cat("loop1"), Err(FSError::SymlinkLoopError)));
+        assert!(matches!(fs.stat("loop2"), Err(FSError::SymlinkLoopError)));
+        assert_eq!(fs.lstat("loop2")?.kind, InodeType::Symlink);
+
+        // Moving a new link over the old one switches where it leads.
+        fs.mkdir("releases/v2")?;
+        fs.create("releases/v2/app")?;
+        fs.append("releases/v2/app", b"v2")?;
+        fs.symlink("releases/v2", "next")?;
+        fs.rename("next", "current")?;
+        assert_eq!(fs.cat("current/app")?, b"v2");
+        fs.unmount()?;
+
+        // Removing a link leaves what it points at alone.
+        let mut fs = FileSys::mount_device(Box::new(disk))?;
+        let long_target = "a/".repeat(300) + "b";
+        fs.symlink(&long_target, "long")?;
+        assert_eq!(fs.readlink("long")?, long_target);
+        fs.cd("current")?;
+        assert_eq!(fs.cat("app")?, b"v2");
+        fs.cd("/")?;
+        fs.rm("current")?;
+        assert!(fs.stat("releases/v2/app").is_ok());
+        for link in ["long", "dangling", "loop1", "loop2"] {
+            fs.rm(link)?;
+        }
+        fs.rm_r("releases")?;
+        assert_eq!(fs.wrapped_file_sys.file_sys().count_free_blocks()?, free_blocks);
+        assert_eq!(fs.wrapped_file_sys.file_sys().count_free_inodes()?, free_inodes);
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_write_at() -> Result<()> {
+        let disk = MemDisk::new(512, 256);
+        let fs = FileSys::format_device(Box::new(disk.clone()), "")?;
+        let free_blocks = fs.wrapped_file_sys.file_sys().count_free_blocks()?;
+        fs.create("file")?;
+        fs.write_at("file", 0, b"hello")?;
+        fs.write_at("file", 3, b"p!")?;
+        assert_eq!(fs.read_at("file", 0, 100)?, b"help!");
+        assert_eq!(fs.wrapped_file_sys.file_sys().count_free_blocks()?, free_blocks);
+
+        // Writing far past the end leaves the blocks in between unallocated.
+        fs.write_at("file", 5000, b"tail")?;
+        assert_eq!(fs.wrapped_file_sys.file_sys().count_free_blocks()?, free_blocks - 2);
+        let stat = fs.stat("file")?;
+        assert_eq!((stat.size, stat.blocks), (5004, 2));
+        assert_eq!(fs.read_at("file", 0, 5)?, b"help!");
+        assert_eq!(fs.read_at("file", 1000, 10)?, vec![0u8; 10]);
+        assert_eq!(fs.read_at("file", 4998, 100)?, b"\0\0tail");
+        assert_eq!(fs.read_at("file", 5004, 1)?, b"");
+
+        // A write straddling mapped blocks and a hole fills only the hole.
+        let data = vec![b'x'; 600];
+        fs.write_at("file", 400, &data)?;
+        assert_eq!(fs.wrapped_file_sys.file_sys().count_free_blocks()?, free_blocks - 3);
+        fs.write_at("file", 2048, b"middle")?;
+        fs.unmount()?;
+
+        let fs = FileSys::mount_device(Box::new(disk))?;
+        assert_eq!(fs.read_at("file", 0, 5)?, b"help!");
+        assert_eq!(fs.read_at("file", 398, 4)?, b"\0\0xx");
+        assert_eq!(fs.read_at("file", 998, 4)?, b"xx\0\0");
+        assert_eq!(fs.read_at("file", 2048, 6)?, b"middle");
+        assert_eq!(fs.read_at("file", 5000, 4)?, b"tail");
+        assert_eq!(fs.wrapped_file_sys.file_sys().count_free_blocks()?, free_blocks - 4);
+        assert_eq!(fs.stat("file")?.blocks, 4);
+        assert!(matches!(fs.write_at("/", 0, b"x"), Err(FSError::InvalidFileNameError)));
+        Ok(())
+    }
+
+    #[test]
+    fn test_truncate_fallocate() -> Result<()> {
+        let disk = MemDisk::new(512, 256);
+        let fs = FileSys::format_device(Box::new(disk.clone()), "")?;
+        let free_blocks = fs.wrapped_file_sys.file_sys().count_free_blocks()?;
+        fs.create("file")?;
+        fs.write_at("file", 0, &[b'a'; 3000])?;
+        assert_eq!(fs.wrapped_file_sys.file_sys().count_free_blocks()?, free_blocks - 6);
+
+        fs.truncate("file", 1000)?;
+        assert_eq!(fs.wrapped_file_sys.file_sys().count_free_blocks()?, free_blocks - 2);
+        assert_eq!(fs.read_at("file", 0, 5000)?, vec![b'a'; 1000]);
+        // Growing again reads zeros past the old end, without allocating anything.
+        fs.truncate("file", 2000)?;
+        assert_eq!(fs.wrapped_file_sys.file_sys().count_free_blocks()?, free_blocks - 2);
+        assert_eq!(fs.read_at("file", 990, 1010)?, [&[b'a'; 10][..], &[0u8; 1000][..]].concat());
+        // No length past the last logical block a file can map.
+        assert!(matches!(fs.truncate("file", usize::MAX), Err(FSError::FileFullError)));
+        assert_eq!(fs.cat("file")?.len(), 2000);
+        fs.truncate("file", 0)?;
+        assert_eq!(fs.wrapped_file_sys.file_sys().count_free_blocks()?, free_blocks);
+        assert_eq!(fs.read_at("file", 0, 10)?, b"");
+
+        // Preallocated blocks read as zeros.
+        fs.write_at("file", 1024, b"kept")?;
+        fs.fallocate("file", 5000)?;
+        assert_eq!(fs.wrapped_file_sys.file_sys().count_free_blocks()?, free_blocks - 10);
+        assert_eq!(fs.read_at("file", 1020, 10)?, b"\0\0\0\0kept\0\0");
+        assert_eq!(fs.read_at("file", 0, 6000)?.len(), 5000);
+        fs.fallocate("file", 100)?;
+        assert_eq!(fs.read_at("file", 0, 6000)?.len(), 5000);
+
+        // Inline files stay inline while they fit.
+        fs.create("small")?;
+        fs.fallocate("small", 100)?;
+        fs.truncate("small", 10)?;
+        assert_eq!(fs.read_at("small", 0, 100)?, vec![0u8; 10]);
+        assert_eq!(fs.wrapped_file_sys.file_sys().count_free_blocks()?, free_blocks - 10);
+        fs.unmount()?;
+
+        let fs = FileSys::mount_device(Box::new(disk))?;
+        assert_eq!(fs.read_at("file", 1024, 4)?, b"kept");
+        fs.truncate("file", 1026)?;
+        assert_eq!(fs.read_at("file", 1020, 10)?, b"\0\0\0\0ke");
+        assert_eq!(fs.wrapped_file_sys.file_sys().count_free_blocks()?, free_blocks - 3);
+        Ok(())
+    }
+
+    #[test]
+    fn test_large_dir() -> Result<()> {
+        let disk = MemDisk::new(512, 4096);
+        let options = FormatOptions { block_size: 512, num_blocks: 4096, dir_index: false, ..FormatOptions::default() };
+        let fs = FileSys::open(BasicFileSys::format_with(Box::new(disk.clone()), &options)?)?;
+        let free_blocks = fs.wrapped_file_sys.file_sys().count_free_blocks()?;
+        for i in 0..1000 {
+            fs.create(&format!("file{i:05}"))?;
+        }
+        // 25 records of 20 bytes fit in each block.
+        assert_eq!(fs.get_working_dir()?.num_blocks(), 40);
+        assert!(!fs.get_working_dir()?.is_indexed());
+        fs.unmount()?;
+
+        let mut fs = FileSys::mount_device(Box::new(disk))?;
+        assert_eq!(fs.get_working_dir()?.list(&fs.wrapped_file_sys)?.len(), 1000);
+        assert!(matches!(fs.create("file00999"), Err(FSError::FileExistsError)));
+        for i in 0..1000 {
+            fs.rm(&format!("file{i:05}"))?;
+        }
+        // Emptied blocks are handed back, all but the first one.
+        assert_eq!(fs.get_working_dir()?.num_blocks(), 1);
+        assert_eq!(fs.ls(".")?, "");
+        assert_eq!(fs.wrapped_file_sys.file_sys().count_free_blocks()?, free_blocks);
+        Ok(())
+    }
+
+    #[test]
+    fn test_dir_index() -> Result<()> {
+        let disk = MemDisk::new(512, 8192);
+        let options = FormatOptions { block_size: 512, num_blocks: 8192, num_inodes: Some(4096), ..FormatOptions::default() };
+        let fs = FileSys::open(BasicFileSys::format_with(Box::new(disk.clone()), &options)?)?;
+        assert!(fs.wrapped_file_sys.file_sys().get_super_block().has_feature_ro_compat(FEATURE_RO_COMPAT_DIR_INDEX));
+        let free_blocks = fs.wrapped_file_sys.file_sys().count_free_blocks()?;
+        // Enough leaves to overflow the root of the index into a second level.
+        for i in 0..3000 {
+            fs.create(&format!("file{i:05}"))?;
+        }
+        assert!(fs.get_working_dir()?.is_indexed());
+        fs.unmount()?;
+
+        let mut fs = FileSys::mount_device(Box::new(disk))?;
+        let working_dir = fs.get_working_dir()?;
+        assert_eq!(working_dir.list(&fs.wrapped_file_sys)?.len(), 3000);
+        for i in 0..3000 {
+            let entry = working_dir.lookup(&fs.wrapped_file_sys, &format!("file{i:05}"))?.unwrap();
+            assert_eq!(entry.get_kind(), InodeType::File);
+        }
+        assert!(working_dir.lookup(&fs.wrapped_file_sys, "file03000")?.is_none());
+        assert!(matches!(fs.create("file01234"), Err(FSError::FileExistsError)));
+        assert!(matches!(fs.cd("file02999"), Err(FSError::NotDirError)));
+
+        // Visit the files out of order, so leaves empty all over the index.
+        for i in 0..3000 {
+            fs.rm(&format!("file{:05}", i * 7 % 3000))?;
+        }
+        // The last leaf going turns the root back into a plain directory block.
+        let working_dir = fs.get_working_dir()?;
+        assert!(!working_dir.is_indexed());
+        assert_eq!(working_dir.num_blocks(), 1);
+        assert_eq!(fs.ls(".")?, "");
+        assert_eq!(fs.wrapped_file_sys.file_sys().count_free_blocks()?, free_blocks);
+        Ok(())
+    }
+
+    #[test]
+    fn test_mount_unformatted() -> Result<()> {
+        let path = temp_image("blank");
+        std::fs::write(&path, [0u8; 16])?;
+        assert!(matches!(BasicFileSys::mount(&path), Err(FSError::NotFormattedError)));
+        std::fs::write(&path, vec![0u8; DEFAULT_BLOCK_SIZE])?;
+        assert!(matches!(BasicFileSys::mount(&path), Err(FSError::NotFormattedError)));
+        std::fs::remove_file(path)?;
+        Ok(())
+    }
+}
@@ -1,154 +1,993 @@
-use std::cell::{RefCell, RefMut};
-use serde::{Serialize, Deserialize};
+use std::cell::RefCell;
 use crate::virtual_file_sys::block::*;
 
 use crate::error::{Result, FSError};
 use crate::virtual_file_sys::file_sys::WrappedFileSys;
+use crate::virtual_file_sys::super_block::{now, FEATURE_INCOMPAT_INLINE_DATA, FEATURE_RO_COMPAT_DIR_INDEX};
 
-pub const UNUSED_ID: u8 = 0;
-pub const HOME_DIR_ID: u8 = 1;
+pub const UNUSED_ID: u32 = 0;
 
-/// Inode - index node for a data file
-#[derive(Debug, Default, Serialize, Deserialize)]
+/// Inode - index node of a file or directory, stored in a slot of the inode table.
+/// `extents` holds the first extents of its data, `indirect` points at a block of
+/// further extents and `double_indirect` at a block of pointers to such blocks.
+///
+/// | offset | size | field                          |
+/// |--------|------|--------------------------------|
+/// | 0      | 16   | inode header                   |
+/// | 16     | 4    | indirect extent block          |
+/// | 20     | 4    | double-indirect pointer block  |
+/// | 24     | 4    | parent inode, for directories  |
+/// | 28     | 2    | mode, permission bits          |
+/// | 30     | 2    | link count                     |
+/// | 32     | 4    | owner user id                  |
+/// | 36     | 4    | owner group id                 |
+/// | 40     | 8    | atime, last access             |
+/// | 48     | 8    | mtime, last change of contents |
+/// | 56     | 8    | ctime, last change of inode    |
+/// | 64     | 192  | inline extents, or inline data |
+///
+/// With `INODE_FLAG_INLINE_DATA` set the last area holds the first `size` bytes
+/// of the file itself and the inode maps no blocks. Times are in seconds since
+/// the Unix epoch.
+#[derive(Debug)]
 pub struct Inode {
-    magic: usize,
+    kind: InodeType,
+    flags: u16,
     size: usize,
-    blocks: Vec<u8>,
+    extents: Vec<Extent>,
+    inline_data: Vec<u8>,
+    indirect: u32,
+    double_indirect: u32,
+    /// Directory holding this one; the root is its own parent.
+    parent: u32,
+    mode: u16,
+    /// Entries naming the inode. A directory also counts its own "." and the
+    /// ".." of each subdirectory.
+    links: u16,
+    uid: u32,
+    gid: u32,
+    atime: u64,
+    mtime: u64,
+    ctime: u64,
+}
+
+impl Inode {
+    pub fn new(kind: InodeType) -> Self {
+        let (mode, links) = match kind {
+            InodeType::File => (DEFAULT_FILE_MODE, 1),
+            InodeType::Dir => (DEFAULT_DIR_MODE, 2),
+            InodeType::Symlink => (DEFAULT_SYMLINK_MODE, 1),
+        };
+        let time = now();
+        Self {
+            kind,
+            flags: 0,
+            size: 0,
+            extents: vec![Extent::default(); INLINE_EXTENTS],
+            inline_data: Vec::new(),
+            indirect: UNUSED_ID,
+            double_indirect: UNUSED_ID,
+            parent: UNUSED_ID,
+            mode,
+            links,
+            uid: 0,
+            gid: 0,
+            atime: time,
+            mtime: time,
+            ctime: time,
+        }
+    }
+
+    /// Inode of a directory held by directory `parent`, whose entries live in block `block_num`.
+    pub fn new_dir(block_num: usize, block_size: usize, parent: usize) -> Self {
+        let mut inode = Self::new(InodeType::Dir);
+        inode.size = block_size;
+        inode.parent = parent as u32;
+        inode.extents[0] = Extent { logical: 0, start: block_num as u32, len: 1 };
+        inode
+    }
+
+    /// Sets the permission bits, leaving out anything above them.
+    pub fn set_mode(&mut self, mode: u16) {
+        self.mode = mode & MODE_MASK;
+        self.changed();
+    }
+
+    pub fn set_owner(&mut self, uid: u32, gid: u32) {
+        self.uid = uid;
+        self.gid = gid;
+        self.changed();
+    }
+
+    pub fn set_times(&mut self, atime: u64, mtime: u64) {
+        self.atime = atime;
+        self.mtime = mtime;
+        self.changed();
+    }
+
+    /// Records a change of the contents.
+    pub fn modified(&mut self) {
+        let time = now();
+        self.mtime = time;
+        self.ctime = time;
+    }
+
+    /// Records a change of the inode alone, such as its mode or links.
+    pub fn changed(&mut self) {
+        self.ctime = now();
+    }
+
+    /// Records a read of the contents.
+    pub fn accessed(&mut self) {
+        self.atime = now();
+    }
 }
 
 impl BlockBinary for Inode {
     fn to_bytes(&self) -> Result<Vec<u8>> {
-        let bytes = bincode::serialize(self)?;
+        let mut bytes = vec![0u8; INODE_SIZE];
+        put_inode_header(&mut bytes, self.kind, self.flags, self.size as u64);
+        put_u32(&mut bytes, 16, self.indirect);
+        put_u32(&mut bytes, 20, self.double_indirect);
+        put_u32(&mut bytes, 24, self.parent);
+        put_u16(&mut bytes, 28, self.mode);
+        put_u16(&mut bytes, 30, self.links);
+        put_u32(&mut bytes, 32, self.uid);
+        put_u32(&mut bytes, 36, self.gid);
+        put_u64(&mut bytes, 40, self.atime);
+        put_u64(&mut bytes, 48, self.mtime);
+        put_u64(&mut bytes, 56, self.ctime);
+        if self.flags & INODE_FLAG_INLINE_DATA != 0 {
+            bytes[INODE_BLOCK_OFFSET..INODE_BLOCK_OFFSET + self.inline_data.len()].copy_from_slice(&self.inline_data);
+        } else {
+            for (i, extent) in self.extents.iter().enumerate() {
+                extent.write(&mut bytes, INODE_BLOCK_OFFSET + i * EXTENT_SIZE);
+            }
+        }
         Ok(bytes)
     }
 
     fn from_bytes(block_data: &[u8]) -> Result<Self> {
-        let block = bincode::deserialize(block_data)?;
-        Ok(block)
+        check_len(block_data, INODE_SIZE)?;
+        let (kind, flags, size) = get_inode_header(block_data)?;
+        let size = size as usize;
+        let mut inode = Self::new(kind);
+        if flags & INODE_FLAG_INLINE_DATA != 0 {
+            if size > INLINE_DATA_SIZE {
+                return Err(FSError::FileSysError);
+            }
+            inode.inline_data = block_data[INODE_BLOCK_OFFSET..INODE_BLOCK_OFFSET + size].to_vec();
+        } else {
+            inode.extents = (0..INLINE_EXTENTS)
+                .map(|i| Extent::read(block_data, INODE_BLOCK_OFFSET + i * EXTENT_SIZE))
+                .collect();
+        }
+        Ok(Self {
+            flags,
+            size,
+            indirect: get_u32(block_data, 16),
+            double_indirect: get_u32(block_data, 20),
+            parent: get_u32(block_data, 24),
+            mode: get_u16(block_data, 28),
+            links: get_u16(block_data, 30),
+            uid: get_u32(block_data, 32),
+            gid: get_u32(block_data, 36),
+            atime: get_u64(block_data, 40),
+            mtime: get_u64(block_data, 48),
+            ctime: get_u64(block_data, 56),
+            ..inode
+        })
+    }
+}
+
+/// Reads inode `ino` from the inode table.
+pub fn read_inode(wrapped_file_sys: &WrappedFileSys, ino: usize) -> Result<Inode> {
+    let mut inode = [0u8; INODE_SIZE];
+    wrapped_file_sys.file_sys().read_inode(ino, &mut inode)?;
+    Inode::from_bytes(&inode)
+}
+
+/// Applies `update` to inode `ino` and writes it back. Not for an inode that is
+/// held open as a `FileInode` or `DirInode`, whose copy would go stale.
+pub fn update_inode<F: FnOnce(&mut Inode)>(wrapped_file_sys: &WrappedFileSys, ino: usize, update: F) -> Result<()> {
+    let mut inode = read_inode(wrapped_file_sys, ino)?;
+    update(&mut inode);
+    wrapped_file_sys.file_sys().write_inode(ino, &inode.to_bytes()?)
+}
+
+/// Metadata of a file or directory, as reported by `stat`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Stat {
+    pub ino: usize,
+    pub kind: InodeType,
+    pub mode: u16,
+    pub links: u16,
+    pub uid: u32,
+    pub gid: u32,
+    pub size: usize,
+    /// Data blocks mapped, fewer than the size calls for when the file has holes.
+    pub blocks: usize,
+    pub atime: u64,
+    pub mtime: u64,
+    pub ctime: u64,
+}
+
+impl Stat {
+    /// Reads the metadata of inode `ino`.
+    pub fn of(wrapped_file_sys: &WrappedFileSys, ino: usize) -> Result<Self> {
+        let inode = read_inode(wrapped_file_sys, ino)?;
+        let Inode { kind, mode, links, uid, gid, size, atime, mtime, ctime, .. } = inode;
+        let blocks = ExtentMap::load(wrapped_file_sys, ino, inode)?.mapped_blocks();
+        Ok(Self { ino, kind, mode, links, uid, gid, size, blocks, atime, mtime, ctime })
+    }
+
+    /// Type and permissions the way `ls -l` shows them, such as "drwxr-xr-x".
+    pub fn mode_string(&self) -> String {
+        let kind = match self.kind {
+            InodeType::File => '-',
+            InodeType::Dir => 'd',
+            InodeType::Symlink => 'l',
+        };
+        let perms = (0..9).rev().map(|bit| match self.mode & (1 << bit) != 0 {
+            true => ['x', 'w', 'r'][bit % 3],
+            false => '-',
+        });
+        std::iter::once(kind).chain(perms).collect()
+    }
+}
+
+impl std::fmt::Display for Stat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Inode: {}  Mode: {:04o} ({})  Links: {}", self.ino, self.mode, self.mode_string(), self.links)?;
+        writeln!(f, "Uid: {}  Gid: {}  Size: {}  Blocks: {}", self.uid, self.gid, self.size, self.blocks)?;
+        writeln!(f, "Access: {}", format_time(self.atime))?;
+        writeln!(f, "Modify: {}", format_time(self.mtime))?;
+        write!(f, "Change: {}", format_time(self.ctime))
     }
 }
 
-#[derive(Serialize, Deserialize)]
+/// `time`, in seconds since the Unix epoch, as a UTC date and time such as
+/// "2024-03-01 12:30:00".
+pub fn format_time(time: u64) -> String {
+    let (days, secs) = (time / 86400, time % 86400);
+    // Civil date from days since the epoch, counting in 400-year eras from 0000-03-01.
+    let z = days + 719468;
+    let era = z / 146097;
+    let doe = z % 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + u64::from(month <= 2);
+    format!("{year:04}-{month:02}-{day:02} {:02}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60)
+}
+
+/// ExtentMap - the blocks mapped by an inode, gathered from its inline extents
+/// and from the extent blocks it points at
+pub struct ExtentMap {
+    id: usize,
+    block_size: usize,
+    raw: RefCell<Inode>,
+    /// Extents of the inode, in logical order.
+    extents: Vec<Extent>,
+    /// Ids of the extent blocks hanging off the double-indirect block.
+    double_indirect_blocks: Vec<usize>,
+}
+
+impl ExtentMap {
+    /// Map of a new inode that has no blocks yet.
+    fn new(id: usize, block_size: usize, inode: Inode) -> Self {
+        Self {
+            id,
+            block_size,
+            raw: RefCell::new(inode),
+            extents: Vec::new(),
+            double_indirect_blocks: Vec::new(),
+        }
+    }
+
+    /// Loads the whole map of inode `id`.
+    fn load(wrapped_file_sys: &WrappedFileSys, id: usize, inode: Inode) -> Result<Self> {
+        let block_size = wrapped_file_sys.file_sys().block_size();
+        let mut extents = inode.extents.clone();
+        let mut double_indirect_blocks = vec![];
+        if inode.indirect != UNUSED_ID {
+            extents.extend(Self::read_extents(wrapped_file_sys, inode.indirect as usize)?);
+        }
+        if inode.double_indirect != UNUSED_ID {
+            let mut block_data = vec![0u8; block_size];
+            wrapped_file_sys.file_sys().read_block(inode.double_indirect as usize, &mut block_data)?;
+            for extent_block in PointerBlock::from_bytes(&block_data)?.pointers {
+                if extent_block == UNUSED_ID {
+                    break;
+                }
+                double_indirect_blocks.push(extent_block as usize);
+                extents.extend(Self::read_extents(wrapped_file_sys, extent_block as usize)?);
+            }
+        }
+        extents.retain(|e| e.len > 0);
+
+        Ok(Self{
+            id,
+            block_size,
+            raw: RefCell::new(inode),
+            extents,
+            double_indirect_blocks,
+        })
+    }
+
+    fn read_extents(wrapped_file_sys: &WrappedFileSys, block_id: usize) -> Result<Vec<Extent>> {
+        let mut block_data = vec![0u8; wrapped_file_sys.file_sys().block_size()];
+        wrapped_file_sys.file_sys().read_block(block_id, &mut block_data)?;
+        Ok(ExtentBlock::from_bytes(&block_data)?.extents)
+    }
+
+    fn write_extents(&self, wrapped_file_sys: &WrappedFileSys, block_id: usize, extents: &[Extent]) -> Result<()> {
+        let mut extent_block = ExtentBlock { extents: extents.to_vec() };
+        extent_block.extents.resize(extents_per_block(self.block_size), Extent::default());
+        wrapped_file_sys.file_sys().write_block(block_id, &extent_block.to_bytes()?)
+    }
+
+    fn new_map_block(wrapped_file_sys: &WrappedFileSys) -> Result<usize> {
+        match wrapped_file_sys.file_sys().get_free_block()? {
+            Some(id) => Ok(id),
+            None => Err(FSError::DiskFullError),
+        }
+    }
+
+    /// Writes the extents from extent `from` onwards back to disk, allocating
+    /// extent blocks the list has grown into and reclaiming the ones it has
+    /// shrunk out of. The new extent blocks are all taken before anything is
+    /// written, so running out of space leaves the map on disk as it was.
+    fn sync_extents(&mut self, wrapped_file_sys: &WrappedFileSys, from: usize) -> Result<()> {
+        let num_inline = INLINE_EXTENTS;
+        let per_block = extents_per_block(self.block_size);
+        let num_extents = self.extents.len();
+        let indirect_range = num_inline..num_inline + per_block;
+        let double_start = indirect_range.end;
+        let needed = num_extents.saturating_sub(double_start).div_ceil(per_block);
+        let mut tmp_raw = self.raw.borrow_mut();
+
+        let wanted = usize::from(num_extents > indirect_range.start && tmp_raw.indirect == UNUSED_ID)
+            + usize::from(needed > 0 && tmp_raw.double_indirect == UNUSED_ID)
+            + needed.saturating_sub(self.double_indirect_blocks.len());
+        let mut new_blocks = Vec::with_capacity(wanted);
+        while new_blocks.len() < wanted {
+            match Self::new_map_block(wrapped_file_sys) {
+                Ok(id) => new_blocks.push(id),
+                Err(e) => {
+                    for id in new_blocks {
+                        wrapped_file_sys.file_sys().reclaim_block(id)?;
+                    }
+                    return Err(e);
+                },
+            }
+        }
+        let mut new_blocks = new_blocks.into_iter();
+
+        for i in from.min(num_inline)..num_inline {
+            tmp_raw.extents[i] = self.extents.get(i).copied().unwrap_or_default();
+        }
+
+        if num_extents > indirect_range.start {
+            if tmp_raw.indirect == UNUSED_ID {
+                tmp_raw.indirect = new_blocks.next().ok_or(FSError::FileSysError)? as u32;
+            }
+            if from < indirect_range.end {
+                let end = num_extents.min(indirect_range.end);
+                self.write_extents(wrapped_file_sys, tmp_raw.indirect as usize, &self.extents[indirect_range.start..end])?;
+            }
+        } else if tmp_raw.indirect != UNUSED_ID {
+            wrapped_file_sys.file_sys().reclaim_block(tmp_raw.indirect as usize)?;
+            tmp_raw.indirect = UNUSED_ID;
+        }
+
+        let resized = needed != self.double_indirect_blocks.len();
+        while self.double_indirect_blocks.len() > needed {
+            let extent_block = self.double_indirect_blocks.pop().unwrap();
+            wrapped_file_sys.file_sys().reclaim_block(extent_block)?;
+        }
+        while self.double_indirect_blocks.len() < needed {
+            self.double_indirect_blocks.push(new_blocks.next().ok_or(FSError::FileSysError)?);
+        }
+        if needed > 0 {
+            if tmp_raw.double_indirect == UNUSED_ID {
+                tmp_raw.double_indirect = new_blocks.next().ok_or(FSError::FileSysError)? as u32;
+            }
+            if resized {
+                let mut pointer_block = PointerBlock {
+                    pointers: self.double_indirect_blocks.iter().map(|&id| id as u32).collect(),
+                };
+                pointer_block.pointers.resize(pointers_per_block(self.block_size), UNUSED_ID);
+                wrapped_file_sys.file_sys().write_block(tmp_raw.double_indirect as usize, &pointer_block.to_bytes()?)?;
+            }
+            for (k, &extent_block) in self.double_indirect_blocks.iter().enumerate() {
+                let start = double_start + k * per_block;
+                if from < start + per_block {
+                    let end = num_extents.min(start + per_block);
+                    self.write_extents(wrapped_file_sys, extent_block, &self.extents[start..end])?;
+                }
+            }
+        } else if tmp_raw.double_indirect != UNUSED_ID {
+            wrapped_file_sys.file_sys().reclaim_block(tmp_raw.double_indirect as usize)?;
+            tmp_raw.double_indirect = UNUSED_ID;
+        }
+
+        wrapped_file_sys.file_sys().write_inode(self.id, &tmp_raw.to_bytes()?)
+    }
+
+    /// Writes the inode itself back to the inode table.
+    fn write_raw(&self, wrapped_file_sys: &WrappedFileSys) -> Result<()> {
+        wrapped_file_sys.file_sys().write_inode(self.id, &self.raw.borrow().to_bytes()?)
+    }
+
+    /// Applies `update` to the inode and writes it back.
+    fn update_raw<F: FnOnce(&mut Inode)>(&self, wrapped_file_sys: &WrappedFileSys, update: F) -> Result<()> {
+        update(&mut self.raw.borrow_mut());
+        self.write_raw(wrapped_file_sys)
+    }
+
+    fn get_links(&self) -> usize {
+        self.raw.borrow().links as usize
+    }
+
+    fn set_links(&self, wrapped_file_sys: &WrappedFileSys, links: usize) -> Result<()> {
+        let links = u16::try_from(links).map_err(|_| FSError::TooManyLinksError)?;
+        self.update_raw(wrapped_file_sys, |inode| {
+            inode.links = links;
+            inode.changed();
+        })
+    }
+
+    /// Number of blocks mapped.
+    pub fn num_blocks(&self) -> usize {
+        self.extents.last().map_or(0, |e| e.logical_end())
+    }
+
+    /// Number of blocks actually allocated, leaving out the holes.
+    pub fn mapped_blocks(&self) -> usize {
+        self.extents.iter().map(|e| e.len as usize).sum()
+    }
+
+    /// Id of the block mapped at logical block `index`.
+    pub fn block_id(&self, index: usize) -> Option<usize> {
+        let pos = self.extents.partition_point(|e| e.logical_end() <= index);
+        self.extents.get(pos)
+            .filter(|e| e.logical as usize <= index)
+            .map(|e| e.start as usize + index - e.logical as usize)
+    }
+
+    /// Block right after the last mapped block, where the next allocation
+    /// should ideally start so the blocks stay contiguous.
+    pub fn allocation_goal(&self) -> Option<usize> {
+        self.extents.last().map(|e| e.start as usize + e.len as usize)
+    }
+
+    /// Appends runs of contiguous blocks, given as (first block, length), to the end of the map.
+    pub fn add_extents(&mut self, wrapped_file_sys: &WrappedFileSys, runs: &[(usize, usize)]) -> Result<()> {
+        let mut extents = self.extents.clone();
+        let from = extents.len().saturating_sub(1);
+        for &(start, len) in runs {
+            let logical = extents.last().map_or(0, |e| e.logical_end());
+            match extents.last_mut() {
+                Some(last) if last.start as usize + last.len as usize == start => {
+                    last.len += len as u32;
+                },
+                _ => extents.push(Extent { logical: logical as u32, start: start as u32, len: len as u32 }),
+            }
+        }
+        if extents.len() > max_extents(self.block_size) {
+            return Err(FSError::FileFullError);
+        }
+        self.extents = extents;
+        self.sync_extents(wrapped_file_sys, from)
+    }
+
+    /// Maps runs of blocks given as (logical block, first block, length) into
+    /// holes of the map, writing the map back once. On failure the map is left
+    /// as it was.
+    pub fn map_extents(&mut self, wrapped_file_sys: &WrappedFileSys, runs: &[(usize, usize, usize)]) -> Result<()> {
+        let mut extents = self.extents.clone();
+        let mut from = usize::MAX;
+        for &(logical, start, len) in runs {
+            let pos = extents.partition_point(|e| e.logical_end() <= logical);
+            if extents.get(pos).is_some_and(|e| (e.logical as usize) < logical + len) {
+                return Err(FSError::FileSysError);
+            }
+            let extent = Extent { logical: logical as u32, start: start as u32, len: len as u32 };
+            let joins_prev = pos > 0 && {
+                let prev = extents[pos - 1];
+                prev.logical_end() == logical && (prev.start + prev.len) as usize == start
+            };
+            let joins_next = extents.get(pos).is_some_and(|next| {
+                next.logical as usize == logical + len && next.start as usize == start + len
+            });
+            match (joins_prev, joins_next) {
+                (true, true) => {
+                    let next = extents.remove(pos);
+                    extents[pos - 1].len += extent.len + next.len;
+                },
+                (true, false) => extents[pos - 1].len += extent.len,
+                (false, true) => {
+                    let next = &mut extents[pos];
+                    *next = Extent { len: extent.len + next.len, ..extent };
+                },
+                (false, false) => extents.insert(pos, extent),
+            }
+            from = from.min(pos.saturating_sub(1));
+        }
+        if extents.len() > max_extents(self.block_size) {
+            return Err(FSError::FileFullError);
+        }
+        let old_extents = std::mem::replace(&mut self.extents, extents);
+        if let Err(e) = self.sync_extents(wrapped_file_sys, from.min(self.extents.len())) {
+            self.extents = old_extents;
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    /// Unmaps every block from logical block `index` on and returns them as runs
+    /// of (first block, length). The blocks themselves are left allocated.
+    pub fn truncate(&mut self, wrapped_file_sys: &WrappedFileSys, index: usize) -> Result<Vec<(usize, usize)>> {
+        let mut pos = self.extents.partition_point(|e| e.logical_end() <= index);
+        let mut runs = vec![];
+        if let Some(extent) = self.extents.get_mut(pos).filter(|e| (e.logical as usize) < index) {
+            let keep = (index - extent.logical as usize) as u32;
+            runs.push(((extent.start + keep) as usize, (extent.len - keep) as usize));
+            extent.len = keep;
+            pos += 1;
+        }
+        runs.extend(self.extents.drain(pos..).map(|e| (e.start as usize, e.len as usize)));
+        self.sync_extents(wrapped_file_sys, pos.saturating_sub(1))?;
+        Ok(runs)
+    }
+
+    /// First logical block from `index` on that is mapped.
+    pub fn next_mapped(&self, index: usize) -> Option<usize> {
+        self.extents.iter()
+            .find(|e| e.logical_end() > index)
+            .map(|e| index.max(e.logical as usize))
+    }
+
+    /// Unmaps block `block_id`, moving the blocks mapped after it down by one.
+    /// The block itself is left allocated.
+    pub fn remove_block(&mut self, wrapped_file_sys: &WrappedFileSys, block_id: usize) -> Result<()> {
+        let index = match self.extents.iter().position(|e| {
+            (e.start as usize..e.start as usize + e.len as usize).contains(&block_id)
+        }) {
+            Some(index) => index,
+            None => return Err(FSError::FileSysError),
+        };
+
+        // Split the extent around the block and shift the blocks after it down by one.
+        let extent = self.extents[index];
+        let offset = (block_id - extent.start as usize) as u32;
+        let before = Extent { len: offset, ..extent };
+        let after = Extent {
+            logical: extent.logical + offset + 1,
+            start: extent.start + offset + 1,
+            len: extent.len - offset - 1,
+        };
+        self.extents.splice(index..=index, [before, after].into_iter().filter(|e| e.len > 0));
+        let removed = extent.logical + offset;
+        for e in self.extents.iter_mut().skip(index) {
+            if e.logical > removed {
+                e.logical -= 1;
+            }
+        }
+        self.sync_extents(wrapped_file_sys, index)
+    }
+
+    #[inline]
+    pub fn has_free_block(&self) -> bool {
+        self.extents.len() < max_extents(self.block_size) || self.num_blocks() == 0
+    }
+}
+
+/// FileInode - a file, or a symbolic link, whose target is kept as the contents
+/// of the inode.
 pub struct FileInode {
     id: usize,
-    magic: usize,
     size: usize,
-    raw: RefCell<Inode>,
-    blocks: Vec<DataBlock>,
+    block_size: usize,
+    map: ExtentMap,
 }
 
 impl FileInode {
     pub fn new(wrapped_file_sys: &WrappedFileSys) -> Result<Self> {
-        let res =  wrapped_file_sys.file_sys().get_free_block();
-        return match res {
+        Self::new_kind(wrapped_file_sys, InodeType::File)
+    }
+
+    /// Creates a symbolic link pointing at `target`.
+    pub fn new_symlink(wrapped_file_sys: &WrappedFileSys, target: &str) -> Result<Self> {
+        let mut link = Self::new_kind(wrapped_file_sys, InodeType::Symlink)?;
+        if let Err(e) = link.write_at(wrapped_file_sys, 0, target.as_bytes()) {
+            Self::retrieve_kind(wrapped_file_sys, link.id, InodeType::Symlink)?.destroy(wrapped_file_sys)?;
+            return Err(e);
+        }
+        Ok(link)
+    }
+
+    fn new_kind(wrapped_file_sys: &WrappedFileSys, kind: InodeType) -> Result<Self> {
+        let block_size = wrapped_file_sys.file_sys().block_size();
+        let res =  wrapped_file_sys.file_sys().allocate_inode()?;
+        match res {
             Some(id) => {
-                let tmp_raw = Inode {
-                    magic: INODE_MAGIC_NUM,
-                    size: 0,
-                    blocks: vec![UNUSED_ID; MAX_DATA_BLOCKS]
+                let inline = wrapped_file_sys.file_sys().get_super_block()
+                    .has_feature_incompat(FEATURE_INCOMPAT_INLINE_DATA);
+                let new_inode = || {
+                    let mut inode = Inode::new(kind);
+                    if inline {
+                        inode.flags = INODE_FLAG_INLINE_DATA;
+                    }
+                    inode
                 };
-                let file_inode = Self {
+                let inode = new_inode();
+                wrapped_file_sys.file_sys().write_inode(id, &inode.to_bytes()?)?;
+                Ok(Self {
                     id,
-                    magic: INODE_MAGIC_NUM,
-                    raw: RefCell::new(Inode::default()),
                     size: 0,
-                    blocks: Vec::new(),
-                };
-                file_inode.write_and_set_raw_block(wrapped_file_sys, tmp_raw)?;
-                Ok(file_inode)
+                    block_size,
+                    map: ExtentMap::new(id, block_size, inode),
+                })
             },
             None => { Err(FSError::DiskFullError) }
         }
     }
 
     pub fn retrieve(wrapped_file_sys: &WrappedFileSys, id: usize) -> Result<Self> {
-        let mut block_data = vec![0u8; BLOCK_SIZE];
-        wrapped_file_sys.file_sys().read_block(id, &mut block_data)?;
-        let inode: Inode = bincode::deserialize(&block_data)?;
-        if inode.magic != INODE_MAGIC_NUM {
-            return Err(FSError::FileSysError);
-        }
-        let mut blocks = vec![];
-        for i in 0..MAX_DATA_BLOCKS {
-            let block_id = inode.blocks[i];
-            if block_id != UNUSED_ID {
-                blocks.push(DataBlock::retrieve(wrapped_file_sys,block_id as usize)?);
-            }
+        Self::retrieve_kind(wrapped_file_sys, id, InodeType::File)
+    }
+
+    pub fn retrieve_symlink(wrapped_file_sys: &WrappedFileSys, id: usize) -> Result<Self> {
+        Self::retrieve_kind(wrapped_file_sys, id, InodeType::Symlink)
+    }
+
+    fn retrieve_kind(wrapped_file_sys: &WrappedFileSys, id: usize, kind: InodeType) -> Result<Self> {
+        let block_size = wrapped_file_sys.file_sys().block_size();
+        let inode = read_inode(wrapped_file_sys, id)?;
+        match (kind, inode.kind) {
+            (expected, found) if expected == found => {},
+            (InodeType::Symlink, _) => return Err(FSError::NotSymlinkError),
+            _ => return Err(FSError::NotAFileError),
         }
         Ok(Self{
             id,
-            magic: inode.magic,
             size: inode.size,
-            raw: RefCell::new(inode),
-            blocks,
+            block_size,
+            map: ExtentMap::load(wrapped_file_sys, id, inode)?,
         })
     }
 
-    pub fn write_and_set_raw_block(&self, wrapped_file_sys: &WrappedFileSys, tmp_raw: Inode) -> Result<()> {
-        wrapped_file_sys.file_sys().write_block(self.id, &tmp_raw.to_bytes()?)?;
-        self.raw.replace(tmp_raw);
-        Ok(())
-    }
-    pub fn get_raw(&self) -> RefMut<Inode> {
-        self.raw.borrow_mut()
-    }
     pub fn get_size(&self) -> usize {
         self.size
     }
 
-    pub fn get_blocks(&self) -> &Vec<DataBlock> {
-        &self.blocks
+    /// Whether the contents of the file are kept in the inode itself.
+    pub fn is_inline(&self) -> bool {
+        self.map.raw.borrow().flags & INODE_FLAG_INLINE_DATA != 0
     }
 
-    pub fn add_block(&mut self, wrapped_file_sys: &WrappedFileSys, block: DataBlock) -> Result<()>{
-        let mut tmp_raw = self.get_raw();
-        for i in 0..MAX_DATA_BLOCKS {
-            if tmp_raw.blocks[i] == UNUSED_ID {
-                tmp_raw.blocks[i] = block.get_id() as u8;
-                wrapped_file_sys.file_sys().write_block(self.id, &tmp_raw.to_bytes()?)?;
-                return Ok(())
+    pub fn get_inline_data(&self) -> Vec<u8> {
+        self.map.raw.borrow().inline_data.clone()
+    }
+
+    /// Replaces the contents of an inline file.
+    pub fn set_inline_data(&mut self, wrapped_file_sys: &WrappedFileSys, data: &[u8]) -> Result<()> {
+        if !self.is_inline() || data.len() > INLINE_DATA_SIZE {
+            return Err(FSError::FileSysError);
+        }
+        let mut tmp_raw = self.map.raw.borrow_mut();
+        tmp_raw.inline_data = data.to_vec();
+        tmp_raw.size = data.len();
+        tmp_raw.modified();
+        self.size = data.len();
+        wrapped_file_sys.file_sys().write_inode(self.id, &tmp_raw.to_bytes()?)
+    }
+
+    /// Turns an inline file into an empty one mapping its data through extents.
+    /// Only the in-memory inode changes; it is written with the first extents added.
+    pub fn clear_inline(&mut self) {
+        let mut tmp_raw = self.map.raw.borrow_mut();
+        tmp_raw.flags &= !INODE_FLAG_INLINE_DATA;
+        tmp_raw.inline_data.clear();
+        tmp_raw.size = 0;
+        self.size = 0;
+    }
+
+    /// Id of the data block holding file block `index`.
+    pub fn block_id(&self, index: usize) -> Option<usize> {
+        self.map.block_id(index)
+    }
+
+    /// Block right after the file's last data block, where the next allocation
+    /// should ideally start so the file stays contiguous.
+    pub fn allocation_goal(&self) -> Option<usize> {
+        self.map.allocation_goal()
+    }
+
+    /// Reads up to `len` bytes from byte `offset`, stopping at the end of the file.
+    /// Holes read as zeros.
+    pub fn read_at(&self, wrapped_file_sys: &WrappedFileSys, offset: usize, len: usize) -> Result<Vec<u8>> {
+        let end = self.size.min(offset.saturating_add(len));
+        if offset >= end {
+            return Ok(Vec::new());
+        }
+        if self.is_inline() {
+            return Ok(self.map.raw.borrow().inline_data[offset..end].to_vec());
+        }
+        let block_size = self.block_size;
+        let mut data = Vec::with_capacity(end - offset);
+        let mut block = vec![0u8; block_size];
+        for index in offset / block_size..=(end - 1) / block_size {
+            let block_start = index * block_size;
+            let from = offset.max(block_start) - block_start;
+            let to = end.min(block_start + block_size) - block_start;
+            match self.block_id(index) {
+                Some(block_id) => {
+                    wrapped_file_sys.file_sys().read_block(block_id, &mut block)?;
+                    data.extend_from_slice(&block[from..to]);
+                },
+                None => data.resize(data.len() + to - from, 0),
             }
         }
-        Err(FSError::FileFullError)
+        Ok(data)
     }
 
-    pub fn remove_block(&mut self, wrapped_file_sys: &WrappedFileSys, block: &DataBlock) -> Result<()> {
-        let mut tmp_raw = self.raw.borrow_mut();
-        return match tmp_raw.blocks.iter().position(|&i| i as usize == block.get_id()) {
-            Some(index) => {
-                tmp_raw.blocks.remove(index);
-                tmp_raw.blocks.push(UNUSED_ID);
-                if let Some(index) = self.blocks.iter()
-                    .position(|b|b.get_id() == block.get_id()) {
-                    self.blocks.remove(index);
+    /// Writes `data` at byte `offset`, allocating blocks for the parts of the file
+    /// it covers that have none. Writing past the end grows the file, leaving a
+    /// hole between the old end and `offset`. The write happens as a whole or,
+    /// when it fails, leaves the contents of the file as they were.
+    pub fn write_at(&mut self, wrapped_file_sys: &WrappedFileSys, offset: usize, data: &[u8]) -> Result<()> {
+        if data.is_empty() {
+            return Ok(());
+        }
+        let end = offset.checked_add(data.len()).ok_or(FSError::FileFullError)?;
+        if (end - 1) / self.block_size > u32::MAX as usize {
+            return Err(FSError::FileFullError);
+        }
+        let new_size = self.size.max(end);
+        if self.is_inline() {
+            if end <= INLINE_DATA_SIZE {
+                let mut content = self.get_inline_data();
+                content.resize(new_size, 0);
+                content[offset..end].copy_from_slice(data);
+                return self.set_inline_data(wrapped_file_sys, &content);
+            }
+            // The contents stay the same when the write itself fails later on.
+            self.move_out_inline(wrapped_file_sys)?;
+        }
+        self.write_blocks(wrapped_file_sys, offset, data, new_size)
+    }
+
+    /// Cuts the file down, or extends it with a hole, to `len` bytes. Blocks past
+    /// the new end are freed and the rest of the last block is zeroed, so growing
+    /// the file again reads zeros there.
+    pub fn truncate(&mut self, wrapped_file_sys: &WrappedFileSys, len: usize) -> Result<()> {
+        if len > 0 && (len - 1) / self.block_size > u32::MAX as usize {
+            return Err(FSError::FileFullError);
+        }
+        if self.is_inline() {
+            if len <= INLINE_DATA_SIZE {
+                let mut content = self.get_inline_data();
+                content.resize(len, 0);
+                return self.set_inline_data(wrapped_file_sys, &content);
+            }
+            self.move_out_inline(wrapped_file_sys)?;
+        }
+        if len < self.size {
+            let block_size = self.block_size;
+            let keep = len.div_ceil(block_size);
+            for (start, run) in self.map.truncate(wrapped_file_sys, keep)? {
+                wrapped_file_sys.file_sys().reclaim_extent(start, run)?;
+            }
+            let tail = len % block_size;
+            if let Some(block_id) = self.block_id(keep.wrapping_sub(1)).filter(|_| tail > 0) {
+                let mut block = vec![0u8; block_size];
+                wrapped_file_sys.file_sys().read_block(block_id, &mut block)?;
+                block[tail..].fill(0);
+                wrapped_file_sys.file_sys().write_block(block_id, &block)?;
+            }
+        }
+        self.set_size(wrapped_file_sys, len)
+    }
+
+    /// Reserves zeroed blocks for every hole in the first `len` bytes of the file,
+    /// growing the file to `len` bytes when it is shorter.
+    pub fn fallocate(&mut self, wrapped_file_sys: &WrappedFileSys, len: usize) -> Result<()> {
+        if len == 0 {
+            return Ok(());
+        }
+        if (len - 1) / self.block_size > u32::MAX as usize {
+            return Err(FSError::FileFullError);
+        }
+        let new_size = self.size.max(len);
+        if self.is_inline() {
+            if len <= INLINE_DATA_SIZE {
+                let mut content = self.get_inline_data();
+                content.resize(new_size, 0);
+                return self.set_inline_data(wrapped_file_sys, &content);
+            }
+            self.move_out_inline(wrapped_file_sys)?;
+        }
+        let runs = self.allocate_holes(wrapped_file_sys, 0, (len - 1) / self.block_size)?;
+        let result = self.write_runs(wrapped_file_sys, &runs, |_, _| {})
+            .and_then(|()| self.commit(wrapped_file_sys, &runs, new_size));
+        if let Err(e) = result {
+            for &(_, start, run) in &runs {
+                wrapped_file_sys.file_sys().reclaim_extent(start, run)?;
+            }
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    /// Moves the contents of an inline file out to data blocks.
+    fn move_out_inline(&mut self, wrapped_file_sys: &WrappedFileSys) -> Result<()> {
+        let content = self.get_inline_data();
+        self.clear_inline();
+        self.write_blocks(wrapped_file_sys, 0, &content, content.len())
+    }
+
+    /// Records the size of the file in bytes, both here and in the inode, and
+    /// writes the inode. Blocks are left as they are.
+    pub fn set_size(&mut self, wrapped_file_sys: &WrappedFileSys, size: usize) -> Result<()> {
+        self.size = size;
+        self.map.update_raw(wrapped_file_sys, |inode| {
+            inode.size = size;
+            inode.modified();
+        })
+    }
+
+    /// Writes `data` over the file blocks from byte `offset` on and sets the size
+    /// of the file to `size`. Holes get new blocks, written before they are mapped
+    /// so a hole never shows stale contents; blocks already mapped are updated in
+    /// place, keeping their old contents until the new blocks and the size are
+    /// in the inode. On failure everything written is put back.
+    fn write_blocks(&mut self, wrapped_file_sys: &WrappedFileSys, offset: usize, data: &[u8], size: usize) -> Result<()> {
+        if data.is_empty() {
+            return self.set_size(wrapped_file_sys, size);
+        }
+        let block_size = self.block_size;
+        let end = offset + data.len();
+        // Lays the part of `data` falling in file block `index` over `block`.
+        let patch = |index: usize, block: &mut [u8]| {
+            let block_start = index * block_size;
+            let from = offset.max(block_start);
+            let to = end.min(block_start + block_size);
+            block[from - block_start..to - block_start].copy_from_slice(&data[from - offset..to - offset]);
+        };
+        let (first, last) = (offset / block_size, (end - 1) / block_size);
+        let runs = self.allocate_holes(wrapped_file_sys, first, last)?;
+        let mut backups = vec![];
+        let result = self.write_runs(wrapped_file_sys, &runs, patch)
+            .and_then(|()| {
+                for index in first..=last {
+                    if let Some(block_id) = self.block_id(index) {
+                        let mut block = vec![0u8; block_size];
+                        wrapped_file_sys.file_sys().read_block(block_id, &mut block)?;
+                        backups.push((block_id, block.clone()));
+                        patch(index, &mut block);
+                        wrapped_file_sys.file_sys().write_block(block_id, &block)?;
+                    }
                 }
-                wrapped_file_sys.file_sys().write_block(self.id, &tmp_raw.to_bytes()?)?;
                 Ok(())
-            },
-            None => {
-                Err(FSError::FileSysError)
+            })
+            .and_then(|()| self.commit(wrapped_file_sys, &runs, size));
+        if let Err(e) = result {
+            for (block_id, block) in backups {
+                wrapped_file_sys.file_sys().write_block(block_id, &block)?;
             }
+            for &(_, start, len) in &runs {
+                wrapped_file_sys.file_sys().reclaim_extent(start, len)?;
+            }
+            return Err(e);
         }
+        Ok(())
     }
 
-    pub fn set_size(&mut self, wrapped_file_sys: &WrappedFileSys, size: usize) -> Result<()>{
-        let mut tmp_raw = self.get_raw();
-        tmp_raw.size = size;
-        wrapped_file_sys.file_sys().write_block(self.id, &tmp_raw.to_bytes()?)?;
+    /// Allocates blocks for every hole between file blocks `first` and `last`,
+    /// as runs of (file block, first block, length) that are not mapped yet.
+    /// Either every hole gets its blocks or nothing stays allocated.
+    fn allocate_holes(&self, wrapped_file_sys: &WrappedFileSys, first: usize, last: usize) -> Result<Vec<(usize, usize, usize)>> {
+        let mut runs: Vec<(usize, usize, usize)> = vec![];
+        let mut index = first;
+        while index <= last {
+            if self.block_id(index).is_some() {
+                index += 1;
+                continue;
+            }
+            let hole_end = self.map.next_mapped(index).unwrap_or(usize::MAX).min(last + 1);
+            // Carry on from the block before, so the file stays contiguous.
+            let goal = match runs.last() {
+                Some(&(logical, start, len)) if logical + len == index => Some(start + len),
+                _ => match index.checked_sub(1).and_then(|prev| self.block_id(prev)) {
+                    Some(prev_block) => Some(prev_block + 1),
+                    None => self.allocation_goal(),
+                },
+            };
+            match wrapped_file_sys.file_sys().allocate_extent(goal, hole_end - index) {
+                Ok(Some((start, len))) => {
+                    runs.push((index, start, len));
+                    index += len;
+                },
+                res => {
+                    for &(_, start, len) in &runs {
+                        wrapped_file_sys.file_sys().reclaim_extent(start, len)?;
+                    }
+                    return Err(res.err().unwrap_or(FSError::DiskFullError));
+                },
+            }
+        }
+        Ok(runs)
+    }
+
+    /// Writes the blocks of `runs` from `allocate_holes`, `fill` laying the
+    /// contents of each file block over zeros.
+    fn write_runs<F: Fn(usize, &mut [u8])>(&self, wrapped_file_sys: &WrappedFileSys, runs: &[(usize, usize, usize)], fill: F) -> Result<()> {
+        for &(logical, start, len) in runs {
+            for k in 0..len {
+                let mut block = vec![0u8; self.block_size];
+                fill(logical + k, &mut block);
+                wrapped_file_sys.file_sys().write_block(start + k, &block)?;
+            }
+        }
         Ok(())
     }
 
-    #[inline]
-    pub fn has_free_block(&self) -> bool {
-        MAX_DATA_BLOCKS - self.blocks.len() > 0
+    /// Maps `runs` into the file and sets its size, in a single write of the map.
+    /// On failure the inode is left as it was; the blocks of `runs` are not reclaimed.
+    fn commit(&mut self, wrapped_file_sys: &WrappedFileSys, runs: &[(usize, usize, usize)], size: usize) -> Result<()> {
+        let old_size = self.map.raw.borrow().size;
+        self.map.raw.borrow_mut().size = size;
+        self.map.raw.borrow_mut().modified();
+        let result = match runs.is_empty() {
+            true => self.map.write_raw(wrapped_file_sys),
+            false => self.map.map_extents(wrapped_file_sys, runs),
+        };
+        match result {
+            Ok(()) => {
+                self.size = size;
+                Ok(())
+            },
+            Err(e) => {
+                self.map.raw.borrow_mut().size = old_size;
+                Err(e)
+            },
+        }
+    }
+
+    pub fn get_links(&self) -> usize {
+        self.map.get_links()
+    }
+
+    pub fn set_links(&self, wrapped_file_sys: &WrappedFileSys, links: usize) -> Result<()> {
+        self.map.set_links(wrapped_file_sys, links)
     }
 
-    pub fn internal_flag_size(&self) -> usize {
-        self.size % BLOCK_SIZE
+    /// Records a read of the file.
+    pub fn accessed(&self, wrapped_file_sys: &WrappedFileSys) -> Result<()> {
+        self.map.update_raw(wrapped_file_sys, Inode::accessed)
+    }
+
+    /// Drops one link to the file, once one of its entries is gone. The file is
+    /// freed along with its last link.
+    pub fn unlink(self, wrapped_file_sys: &WrappedFileSys) -> Result<()> {
+        match self.get_links() {
+            0 | 1 => self.destroy(wrapped_file_sys),
+            links => self.set_links(wrapped_file_sys, links - 1),
+        }
     }
 
-    pub fn destroy(self, wrapped_file_sys: &WrappedFileSys) {
-        wrapped_file_sys.file_sys().reclaim_block(self.id);
+    /// Frees the data blocks of the file, the extent blocks mapping them and the
+    /// inode itself.
+    pub fn destroy(mut self, wrapped_file_sys: &WrappedFileSys) -> Result<()> {
+        for (start, len) in self.map.truncate(wrapped_file_sys, 0)? {
+            wrapped_file_sys.file_sys().reclaim_extent(start, len)?;
+        }
+        wrapped_file_sys.file_sys().reclaim_inode(self.id)
     }
 
     pub fn get_id(&self) -> usize {
@@ -156,156 +995,418 @@ impl FileInode {
     }
 }
 
-#[derive(Default,Serialize, Deserialize)]
+/// DirInode - a directory, whose entries are kept in directory blocks.
+///
+/// A directory starts out with a single block that is searched record by record.
+/// On a file system with `FEATURE_RO_COMPAT_DIR_INDEX`, a directory outgrowing
+/// that block turns it into the root of a hashed index (`INODE_FLAG_INDEX`):
+/// entries are spread over leaf blocks by the hash of their name, so finding one
+/// only reads the index blocks on the way down and a single leaf.
 pub struct DirInode {
     id: usize,
-    magic: usize,
-    num_entries: usize,
-    raw:RefCell<DirBlock>,
-    file_entries: Vec<DirEntry>,
-    dir_entries: Vec<DirEntry>,
+    block_size: usize,
+    /// Blocks holding the entries of the directory, and its index if it has one.
+    map: ExtentMap,
 }
 
+/// Index blocks walked through from the root down to a leaf, with their block numbers.
+type IndexPath = Vec<(usize, IndexBlock)>;
+
 impl DirInode {
-    pub fn new(wrapped_file_sys: &WrappedFileSys) -> Result<Self> {
-        let res = wrapped_file_sys.file_sys().get_free_block();
-        return match res {
-            Some(id) => {
-                let tmp_raw = DirBlock::new();
-                wrapped_file_sys.file_sys().write_block(id, &tmp_raw.to_bytes()?)?;
-                let dir_inode = Self {
-                    id,
-                    magic: DIR_MAGIC_NUM,
-                    num_entries: 0,
-                    raw: RefCell::new(tmp_raw),
-                    file_entries: Vec::new(),
-                    dir_entries: Vec::new(),
-                };
-                Ok(dir_inode)
-            },
-            None => { Err(FSError::DiskFullError) }
-        }
+    /// Creates an empty directory inside directory `parent`.
+    pub fn new(wrapped_file_sys: &WrappedFileSys, parent: usize) -> Result<Self> {
+        let block_size = wrapped_file_sys.file_sys().block_size();
+        let id = match wrapped_file_sys.file_sys().allocate_inode()? {
+            Some(id) => id,
+            None => return Err(FSError::DiskFullError),
+        };
+        let block_num = match wrapped_file_sys.file_sys().get_free_block()? {
+            Some(block_num) => block_num,
+            None => {
+                wrapped_file_sys.file_sys().reclaim_inode(id)?;
+                return Err(FSError::DiskFullError);
+            }
+        };
+        let dir_block = DirBlock::new(block_size);
+        wrapped_file_sys.file_sys().write_block(block_num, &dir_block.to_bytes()?)?;
+        let inode = Inode::new_dir(block_num, block_size, parent);
+        wrapped_file_sys.file_sys().write_inode(id, &inode.to_bytes()?)?;
+        Self::load(wrapped_file_sys, id, inode)
     }
 
     pub fn retrieve(wrapped_file_sys: &WrappedFileSys, id: usize) -> Result<Self> {
-        let mut block_data = vec![0u8; BLOCK_SIZE];
-        wrapped_file_sys.file_sys().read_block(id, &mut block_data)?;
-        let dir_block: DirBlock = bincode::deserialize(&block_data)?;
-        if dir_block.magic != DIR_MAGIC_NUM {
-            return Err(FSError::FileSysError);
+        let inode = read_inode(wrapped_file_sys, id)?;
+        if inode.kind != InodeType::Dir {
+            return Err(FSError::NotDirError);
         }
-        let mut dir_node = DirInode::default();
-        for i in 0..MAX_DIR_ENTRIES {
-            let name = dir_block.dir_entries[i].name;
-            let block_id = dir_block.dir_entries[i].block_num;
-            if block_id != UNUSED_ID as usize{
-                let mut block_data = vec![0u8; BLOCK_SIZE];
-                wrapped_file_sys.file_sys().read_block(block_id, &mut block_data)?;
-                if let Ok(entry) = bincode::deserialize::<Inode>(&block_data) {
-                    if entry.magic == INODE_MAGIC_NUM {
-                        dir_node.file_entries.push(DirEntry::new(name, block_id));
-                    }
-                }
-                if let Ok(entry) = bincode::deserialize::<DirBlock>(&block_data) {
-                    if entry.magic == DIR_MAGIC_NUM{
-                         dir_node.dir_entries.push(DirEntry::new(name, block_id));
-                    }
-                }
+        Self::load(wrapped_file_sys, id, inode)
+    }
+
+    fn load(wrapped_file_sys: &WrappedFileSys, id: usize, inode: Inode) -> Result<Self> {
+        let block_size = wrapped_file_sys.file_sys().block_size();
+        Ok(Self {
+            id,
+            block_size,
+            map: ExtentMap::load(wrapped_file_sys, id, inode)?,
+        })
+    }
+
+    /// Inode of the directory holding this one.
+    pub fn get_parent(&self) -> usize {
+        self.map.raw.borrow().parent as usize
+    }
+
+    pub fn set_parent(&mut self, wrapped_file_sys: &WrappedFileSys, parent: usize) -> Result<()> {
+        self.map.update_raw(wrapped_file_sys, |inode| {
+            inode.parent = parent as u32;
+            inode.changed();
+        })
+    }
+
+    /// Links to the directory: its entry in the parent, its own "." and the ".."
+    /// of each subdirectory.
+    pub fn get_links(&self) -> usize {
+        self.map.get_links()
+    }
+
+    pub fn set_links(&self, wrapped_file_sys: &WrappedFileSys, links: usize) -> Result<()> {
+        self.map.set_links(wrapped_file_sys, links)
+    }
+
+    /// Records a listing of the directory.
+    pub fn accessed(&self, wrapped_file_sys: &WrappedFileSys) -> Result<()> {
+        self.map.update_raw(wrapped_file_sys, Inode::accessed)
+    }
+
+    /// Whether the entries are reached through a hashed index.
+    pub fn is_indexed(&self) -> bool {
+        self.map.raw.borrow().flags & INODE_FLAG_INDEX != 0
+    }
+
+    fn read_dir_block(&self, wrapped_file_sys: &WrappedFileSys, block_num: usize) -> Result<DirBlock> {
+        let mut block_data = vec![0u8; self.block_size];
+        wrapped_file_sys.file_sys().read_block(block_num, &mut block_data)?;
+        DirBlock::from_bytes(&block_data)
+    }
+
+    fn write_dir_block(&self, wrapped_file_sys: &WrappedFileSys, block_num: usize, dir_block: &DirBlock) -> Result<()> {
+        wrapped_file_sys.file_sys().write_block(block_num, &dir_block.to_bytes()?)
+    }
+
+    fn read_index_block(&self, wrapped_file_sys: &WrappedFileSys, block_num: usize) -> Result<IndexBlock> {
+        let mut block_data = vec![0u8; self.block_size];
+        wrapped_file_sys.file_sys().read_block(block_num, &mut block_data)?;
+        IndexBlock::from_bytes(&block_data)
+    }
+
+    fn write_index_block(&self, wrapped_file_sys: &WrappedFileSys, block_num: usize, index_block: &IndexBlock) -> Result<()> {
+        wrapped_file_sys.file_sys().write_block(block_num, &index_block.to_bytes()?)
+    }
+
+    /// Numbers of the blocks of the directory, in logical order.
+    fn block_nums(&self) -> Vec<usize> {
+        (0..self.map.num_blocks()).filter_map(|index| self.map.block_id(index)).collect()
+    }
+
+    /// Number of blocks the directory takes, index blocks included.
+    pub fn num_blocks(&self) -> usize {
+        self.map.num_blocks()
+    }
+
+    /// Every entry of the directory. Index blocks read as empty directory blocks,
+    /// so indexed directories are listed the same way.
+    pub fn list(&self, wrapped_file_sys: &WrappedFileSys) -> Result<Vec<DirEntry>> {
+        let mut entries = vec![];
+        for block_num in self.block_nums() {
+            for record in self.read_dir_block(wrapped_file_sys, block_num)?.dir_entries {
+                entries.push(DirEntry::from_record(record)?);
             }
         }
-        dir_node.id = id;
-        dir_node.magic = dir_block.magic;
-        dir_node.num_entries = dir_block.num_entries;
-        dir_node.raw.replace(dir_block);
-        Ok(dir_node)
+        Ok(entries)
     }
 
-    pub fn write_and_set_raw_block(&self, wrapped_file_sys: &WrappedFileSys, tmp_raw: DirBlock) -> Result<()> {
-        wrapped_file_sys.file_sys().write_block(self.id, &tmp_raw.to_bytes()?)?;
-        self.raw.replace(tmp_raw);
-        Ok(())
+    /// Finds the entry called `name`. Child inodes are never read: the type of
+    /// an entry is recorded next to its name.
+    pub fn lookup(&self, wrapped_file_sys: &WrappedFileSys, name: &str) -> Result<Option<DirEntry>> {
+        match self.find_record(wrapped_file_sys, name)? {
+            Some((_, mut dir_block, index)) => DirEntry::from_record(dir_block.dir_entries.swap_remove(index)).map(Some),
+            None => Ok(None),
+        }
     }
 
-    pub fn get_num_entries(&self) -> usize {
-        self.num_entries
+    /// Block holding the record called `name`, with its number and the position of the record in it.
+    fn find_record(&self, wrapped_file_sys: &WrappedFileSys, name: &str) -> Result<Option<(usize, DirBlock, usize)>> {
+        let block_nums = match self.is_indexed() {
+            true => vec![self.find_leaf(wrapped_file_sys, name_hash(name))?.1],
+            false => self.block_nums(),
+        };
+        for block_num in block_nums {
+            let dir_block = self.read_dir_block(wrapped_file_sys, block_num)?;
+            if let Some(index) = dir_block.dir_entries.iter().position(|e| e.name == name) {
+                return Ok(Some((block_num, dir_block, index)));
+            }
+        }
+        Ok(None)
     }
 
-    pub fn get_file_inode_entries(&self) -> &Vec<DirEntry> {
-        &self.file_entries
+    /// Points the existing entry with the name of `entry` at the inode of `entry`,
+    /// rewriting its record in place.
+    pub fn replace_entry(&mut self, wrapped_file_sys: &WrappedFileSys, entry: DirEntry) -> Result<()> {
+        match self.find_record(wrapped_file_sys, entry.get_name())? {
+            Some((block_num, mut dir_block, index)) => {
+                dir_block.dir_entries[index] = entry.to_record();
+                self.write_dir_block(wrapped_file_sys, block_num, &dir_block)?;
+                self.map.update_raw(wrapped_file_sys, Inode::modified)
+            },
+            None => Err(FSError::FileNotFoundError),
+        }
     }
 
-    pub fn get_dir_inode_entries(&self) -> &Vec<DirEntry> {
-        &self.dir_entries
+    /// Whether the directory has no entries.
+    pub fn is_empty(&self, wrapped_file_sys: &WrappedFileSys) -> Result<bool> {
+        for block_num in self.block_nums() {
+            if self.read_dir_block(wrapped_file_sys, block_num)?.num_entries() > 0 {
+                return Ok(false);
+            }
+        }
+        Ok(true)
     }
 
-    pub fn add_file_entry(&mut self, wrapped_file_sys: &WrappedFileSys, entry: DirEntry) -> Result<()>{
-        let mut tmp_raw = self.raw.borrow_mut();
-        for i in 0..MAX_DIR_ENTRIES {
-            if tmp_raw.dir_entries[i].block_num == UNUSED_ID as usize{
-                tmp_raw.dir_entries[i].block_num = entry.get_id();
-                tmp_raw.dir_entries[i].name = entry.get_name();
-                tmp_raw.num_entries += 1;
-                self.num_entries = tmp_raw.num_entries;
-                self.file_entries.push(entry);
-                wrapped_file_sys.file_sys().write_block(self.id, &tmp_raw.to_bytes()?)?;
-                return Ok(())
+    /// Walks the index from its root down to the leaf covering `hash`, returning
+    /// the index blocks on the way and the number of the leaf.
+    fn find_leaf(&self, wrapped_file_sys: &WrappedFileSys, hash: u32) -> Result<(IndexPath, usize)> {
+        let mut block_num = self.map.block_id(0).ok_or(FSError::FileSysError)?;
+        let mut path: IndexPath = vec![];
+        loop {
+            let node = self.read_index_block(wrapped_file_sys, block_num)?;
+            // Each level down has one level less below it; anything else is a corrupt index.
+            if path.last().is_some_and(|(_, parent)| parent.levels != node.levels + 1) {
+                return Err(FSError::FileSysError);
             }
+            let child = node.child(hash)?;
+            let levels = node.levels;
+            path.push((block_num, node));
+            if levels == 0 {
+                return Ok((path, child));
+            }
+            block_num = child;
         }
-        Err(FSError::DirFullError)
     }
 
-    pub fn add_dir_entry(&mut self, wrapped_file_sys: &WrappedFileSys, entry: DirEntry) -> Result<()>{
-        let mut tmp_raw = self.raw.borrow_mut();
-        for i in 0..MAX_DIR_ENTRIES {
-            if tmp_raw.dir_entries[i].block_num == UNUSED_ID as usize{
-                tmp_raw.dir_entries[i].block_num = entry.get_id();
-                tmp_raw.dir_entries[i].name = entry.get_name();
-                tmp_raw.num_entries += 1;
-                self.num_entries = tmp_raw.num_entries;
-                self.dir_entries.push(entry);
-                wrapped_file_sys.file_sys().write_block(self.id, &tmp_raw.to_bytes()?)?;
-                return Ok(())
+    /// Adds `entry`, growing the directory when no block has room for it.
+    pub fn add_entry(&mut self, wrapped_file_sys: &WrappedFileSys, entry: DirEntry) -> Result<()> {
+        self.insert_entry(wrapped_file_sys, entry)?;
+        self.map.update_raw(wrapped_file_sys, Inode::modified)
+    }
+
+    fn insert_entry(&mut self, wrapped_file_sys: &WrappedFileSys, entry: DirEntry) -> Result<()> {
+        let record = entry.to_record();
+        if self.is_indexed() {
+            return self.insert_indexed(wrapped_file_sys, record);
+        }
+        for block_num in self.block_nums() {
+            let mut dir_block = self.read_dir_block(wrapped_file_sys, block_num)?;
+            if dir_block.has_room_for(record.name.len()) {
+                dir_block.dir_entries.push(record);
+                return self.write_dir_block(wrapped_file_sys, block_num, &dir_block);
             }
         }
-        Err(FSError::DirFullError)
+        let dir_index = wrapped_file_sys.file_sys().get_super_block()
+            .has_feature_ro_compat(FEATURE_RO_COMPAT_DIR_INDEX);
+        if dir_index && self.map.num_blocks() == 1 {
+            self.build_index(wrapped_file_sys)?;
+            return self.insert_indexed(wrapped_file_sys, record);
+        }
+        let block_num = self.grow(wrapped_file_sys)?;
+        let mut dir_block = DirBlock::new(self.block_size);
+        dir_block.dir_entries.push(record);
+        self.write_dir_block(wrapped_file_sys, block_num, &dir_block)
     }
 
-    pub fn remove_file_entry(&mut self, wrapped_file_sys: &WrappedFileSys, block_id: usize) -> Result<()> {
-        let mut tmp_raw = self.raw.borrow_mut();
-        for i in 0..MAX_DIR_ENTRIES {
-            if tmp_raw.dir_entries[i].block_num == block_id {
-                tmp_raw.num_entries -= 1;
-                self.num_entries = tmp_raw.num_entries;
-                wrapped_file_sys.file_sys().write_block(self.id, &tmp_raw.to_bytes()?)?;
-                if let Some(index) = self.file_entries.iter().position(|e| e.get_id() == block_id) {
-                    self.file_entries.remove(index);
+    /// Turns the single, full block of the directory into the root of an index
+    /// with one leaf, which takes over the entries.
+    fn build_index(&mut self, wrapped_file_sys: &WrappedFileSys) -> Result<()> {
+        let root_num = self.map.block_id(0).ok_or(FSError::FileSysError)?;
+        let dir_block = self.read_dir_block(wrapped_file_sys, root_num)?;
+        let leaf_num = self.grow(wrapped_file_sys)?;
+        self.write_dir_block(wrapped_file_sys, leaf_num, &dir_block)?;
+        let root = IndexBlock::new(self.block_size, 0, vec![(0, leaf_num as u32)]);
+        self.write_index_block(wrapped_file_sys, root_num, &root)?;
+        self.map.raw.borrow_mut().flags |= INODE_FLAG_INDEX;
+        self.map.write_raw(wrapped_file_sys)
+    }
+
+    /// Puts `record` in the leaf covering the hash of its name, splitting the
+    /// leaf in two when it is full.
+    fn insert_indexed(&mut self, wrapped_file_sys: &WrappedFileSys, record: Entry) -> Result<()> {
+        let (mut path, leaf_num) = self.find_leaf(wrapped_file_sys, name_hash(&record.name))?;
+        let mut leaf = self.read_dir_block(wrapped_file_sys, leaf_num)?;
+        let fits = leaf.has_room_for(record.name.len());
+        leaf.dir_entries.push(record);
+        if fits {
+            return self.write_dir_block(wrapped_file_sys, leaf_num, &leaf);
+        }
+        let (split_hash, upper) = leaf.split()?;
+        let upper_num = self.grow(wrapped_file_sys)?;
+        if let Err(e) = self.insert_index_entry(wrapped_file_sys, &mut path, split_hash, upper_num) {
+            self.release_block(wrapped_file_sys, upper_num)?;
+            return Err(e);
+        }
+        self.write_dir_block(wrapped_file_sys, upper_num, &upper)?;
+        self.write_dir_block(wrapped_file_sys, leaf_num, &leaf)
+    }
+
+    /// Adds an entry for `child`, covering hashes from `hash` on, to the last index
+    /// block of `path`. A full index block is split in two and the upper half
+    /// linked from its parent; a full root moves its entries down into two new
+    /// index blocks and the index gets one level deeper.
+    fn insert_index_entry(&mut self, wrapped_file_sys: &WrappedFileSys, path: &mut IndexPath, hash: u32, child: usize) -> Result<()> {
+        let (node_num, mut node) = path.pop().ok_or(FSError::FileSysError)?;
+        if node.entries.len() < index_entries_per_block(self.block_size) {
+            node.insert(hash, child);
+            return self.write_index_block(wrapped_file_sys, node_num, &node);
+        }
+        let mut upper = node.split();
+        let upper_hash = upper.entries[0].0;
+        match hash >= upper_hash {
+            true => upper.insert(hash, child),
+            false => node.insert(hash, child),
+        }
+        let upper_num = self.grow(wrapped_file_sys)?;
+        if path.is_empty() {
+            let lower_num = match self.grow(wrapped_file_sys) {
+                Ok(lower_num) => lower_num,
+                Err(e) => {
+                    self.release_block(wrapped_file_sys, upper_num)?;
+                    return Err(e);
                 }
+            };
+            let root = IndexBlock::new(self.block_size, node.levels + 1,
+                vec![(0, lower_num as u32), (upper_hash, upper_num as u32)]);
+            self.write_index_block(wrapped_file_sys, lower_num, &node)?;
+            self.write_index_block(wrapped_file_sys, upper_num, &upper)?;
+            return self.write_index_block(wrapped_file_sys, node_num, &root);
+        }
+        if let Err(e) = self.insert_index_entry(wrapped_file_sys, path, upper_hash, upper_num) {
+            self.release_block(wrapped_file_sys, upper_num)?;
+            return Err(e);
+        }
+        self.write_index_block(wrapped_file_sys, upper_num, &upper)?;
+        self.write_index_block(wrapped_file_sys, node_num, &node)
+    }
+
+    /// Adds an empty block to the end of the directory and returns its number.
+    fn grow(&mut self, wrapped_file_sys: &WrappedFileSys) -> Result<usize> {
+        if !self.map.has_free_block() {
+            return Err(FSError::DirFullError);
+        }
+        let goal = self.map.allocation_goal();
+        let block_num = match wrapped_file_sys.file_sys().allocate_extent(goal, 1)? {
+            Some((block_num, _)) => block_num,
+            None => return Err(FSError::DiskFullError),
+        };
+        let dir_block = DirBlock::new(self.block_size);
+        wrapped_file_sys.file_sys().write_block(block_num, &dir_block.to_bytes()?)?;
+        self.map.raw.borrow_mut().size += self.block_size;
+        if let Err(e) = self.map.add_extents(wrapped_file_sys, &[(block_num, 1)]) {
+            self.map.raw.borrow_mut().size -= self.block_size;
+            wrapped_file_sys.file_sys().reclaim_block(block_num)?;
+            return Err(e);
+        }
+        Ok(block_num)
+    }
+
+    /// Unmaps block `block_num` from the directory and frees it.
+    fn release_block(&mut self, wrapped_file_sys: &WrappedFileSys, block_num: usize) -> Result<()> {
+        self.map.raw.borrow_mut().size -= self.block_size;
+        self.map.remove_block(wrapped_file_sys, block_num)?;
+        wrapped_file_sys.file_sys().reclaim_block(block_num)
+    }
+
+    /// Drops the entry called `name`, handing back blocks left empty.
+    /// The first block is kept so the directory always has one.
+    pub fn remove_entry(&mut self, wrapped_file_sys: &WrappedFileSys, name: &str) -> Result<()> {
+        self.delete_entry(wrapped_file_sys, name)?;
+        self.map.update_raw(wrapped_file_sys, Inode::modified)
+    }
+
+    fn delete_entry(&mut self, wrapped_file_sys: &WrappedFileSys, name: &str) -> Result<()> {
+        if self.is_indexed() {
+            return self.remove_indexed(wrapped_file_sys, name);
+        }
+        for (index, block_num) in self.block_nums().into_iter().enumerate() {
+            let mut dir_block = self.read_dir_block(wrapped_file_sys, block_num)?;
+            let before = dir_block.num_entries();
+            dir_block.dir_entries.retain(|e| e.name != name);
+            if dir_block.num_entries() == before {
+                continue;
             }
+            return match dir_block.num_entries() == 0 && index > 0 {
+                true => self.release_block(wrapped_file_sys, block_num),
+                false => self.write_dir_block(wrapped_file_sys, block_num, &dir_block),
+            };
         }
         Ok(())
     }
 
-    pub fn remove_dir_entry(&mut self, wrapped_file_sys: &WrappedFileSys, block_id: usize) -> Result<()> {
-        let mut tmp_raw = self.raw.borrow_mut();
-        for i in 0..MAX_DIR_ENTRIES {
-            if tmp_raw.dir_entries[i].block_num == block_id {
-                tmp_raw.num_entries -= 1;
-                self.num_entries = tmp_raw.num_entries;
-                wrapped_file_sys.file_sys().write_block(self.id, &tmp_raw.to_bytes()?)?;
-                if let Some(index) = self.dir_entries.iter().position(|e| e.get_id() == block_id) {
-                    self.dir_entries.remove(index);
-                }
+    /// Drops the entry called `name` from its leaf. A leaf left empty is unlinked
+    /// from the index along with the index blocks it leaves empty, and once the
+    /// last leaf goes the root turns back into a plain directory block.
+    fn remove_indexed(&mut self, wrapped_file_sys: &WrappedFileSys, name: &str) -> Result<()> {
+        let hash = name_hash(name);
+        let (mut path, leaf_num) = self.find_leaf(wrapped_file_sys, hash)?;
+        let mut leaf = self.read_dir_block(wrapped_file_sys, leaf_num)?;
+        let before = leaf.num_entries();
+        leaf.dir_entries.retain(|e| e.name != name);
+        if leaf.num_entries() == before {
+            return Ok(());
+        }
+        if leaf.num_entries() > 0 {
+            return self.write_dir_block(wrapped_file_sys, leaf_num, &leaf);
+        }
+        self.release_block(wrapped_file_sys, leaf_num)?;
+        while let Some((node_num, mut node)) = path.pop() {
+            node.remove(hash);
+            if !node.entries.is_empty() {
+                return self.write_index_block(wrapped_file_sys, node_num, &node);
+            }
+            if path.is_empty() {
+                self.write_dir_block(wrapped_file_sys, node_num, &DirBlock::new(self.block_size))?;
+                self.map.raw.borrow_mut().flags &= !INODE_FLAG_INDEX;
+                return self.map.write_raw(wrapped_file_sys);
             }
+            self.release_block(wrapped_file_sys, node_num)?;
         }
         Ok(())
     }
 
-    pub fn has_free_entry(&self) -> bool {
-        MAX_DIR_ENTRIES - self.num_entries > 0
+    /// Whether an entry called `name` can still be added, possibly by growing the directory.
+    pub fn has_room_for(&self, wrapped_file_sys: &WrappedFileSys, name: &str) -> Result<bool> {
+        if self.map.has_free_block() {
+            return Ok(true);
+        }
+        let block_nums = match self.is_indexed() {
+            true => vec![self.find_leaf(wrapped_file_sys, name_hash(name))?.1],
+            false => self.block_nums(),
+        };
+        for block_num in block_nums {
+            if self.read_dir_block(wrapped_file_sys, block_num)?.has_room_for(name.len()) {
+                return Ok(true);
+            }
+        }
+        Ok(false)
     }
 
-    pub fn destroy(self, wrapped_file_sys: &WrappedFileSys) {
-        wrapped_file_sys.file_sys().reclaim_block(self.id);
+    pub fn destroy(self, wrapped_file_sys: &WrappedFileSys) -> Result<()> {
+        for index in 0..self.map.num_blocks() {
+            if let Some(block_num) = self.map.block_id(index) {
+                wrapped_file_sys.file_sys().reclaim_block(block_num)?;
+            }
+        }
+        let mut map = self.map;
+        map.extents.clear();
+        map.sync_extents(wrapped_file_sys, 0)?;
+        wrapped_file_sys.file_sys().reclaim_inode(self.id)
     }
 
     pub fn get_id(&self) -> usize {
@@ -313,25 +1414,40 @@ impl DirInode {
     }
 }
 
-#[derive(Serialize, Deserialize)]
 pub struct DirEntry {
-    name: [u8; MAX_FNAME_SIZE],
+    name: String,
     inode_id: usize,
-
+    kind: InodeType,
 }
 
 impl DirEntry {
 
-    pub fn new(name: [u8; MAX_FNAME_SIZE], inode_id: usize) -> Self {
+    pub fn new(name: &str, inode_id: usize, kind: InodeType) -> Self {
         Self{
-            name,
-            inode_id
+            name: name.to_owned(),
+            inode_id,
+            kind,
+        }
+    }
+
+    fn from_record(record: Entry) -> Result<Self> {
+        match InodeType::from_tag(record.file_type as u16) {
+            Some(kind) => Ok(Self { name: record.name, inode_id: record.ino as usize, kind }),
+            None => Err(FSError::FileSysError),
+        }
+    }
+
+    fn to_record(&self) -> Entry {
+        Entry {
+            ino: self.inode_id as u32,
+            file_type: self.kind as u8,
+            name: self.name.clone(),
         }
     }
 
     #[inline]
-    pub fn get_name(&self) -> [u8; MAX_FNAME_SIZE] {
-        self.name
+    pub fn get_name(&self) -> &str {
+        &self.name
     }
 
     #[inline]
@@ -339,40 +1455,332 @@ impl DirEntry {
         self.inode_id
     }
 
+    #[inline]
+    pub fn get_kind(&self) -> InodeType {
+        self.kind
+    }
+
 }
 
-/// Directory block - represents a directory
-#[derive(Debug, Default,Serialize, Deserialize)]
+/// Directory block - the entries of a directory, filling one data block.
+///
+/// Records are laid out back to back and the last one is stretched to the end
+/// of the block, so every byte of the block belongs to exactly one record. A
+/// block without entries holds a single unused record spanning all of it.
+#[derive(Debug, Default)]
 pub struct DirBlock {
-    magic: usize,
-    num_entries: usize,
+    block_size: usize,
     dir_entries: Vec<Entry>
 }
 
 impl BlockBinary for DirBlock {
     fn to_bytes(&self) -> Result<Vec<u8>> {
-        let bytes = bincode::serialize(self)?;
+        let mut bytes = vec![0u8; self.block_size];
+        let mut offset = 0;
+        for (i, entry) in self.dir_entries.iter().enumerate() {
+            let rec_len = match i + 1 == self.dir_entries.len() {
+                true => self.block_size - offset,
+                false => entry.record_len(),
+            };
+            entry.write(&mut bytes, offset, rec_len);
+            offset += rec_len;
+        }
+        if self.dir_entries.is_empty() {
+            Entry::default().write(&mut bytes, 0, self.block_size);
+        }
         Ok(bytes)
     }
 
     fn from_bytes(block_data: &[u8]) -> Result<Self> {
-        let block = bincode::deserialize(block_data)?;
-        Ok(block)
+        let mut dir_entries = vec![];
+        let mut offset = 0;
+        while offset < block_data.len() {
+            let (entry, rec_len) = Entry::read(block_data, offset)?;
+            if entry.ino != UNUSED_ID {
+                dir_entries.push(entry);
+            }
+            offset += rec_len;
+        }
+        Ok(Self { block_size: block_data.len(), dir_entries })
     }
 }
 
 impl DirBlock {
-    pub fn new() -> Self {
+    pub fn new(block_size: usize) -> Self {
         Self{
-            magic: DIR_MAGIC_NUM,
-            num_entries: 0,
-            dir_entries: vec![Entry::default(); MAX_DIR_ENTRIES]
+            block_size,
+            dir_entries: Vec::new(),
+        }
+    }
+
+    pub fn num_entries(&self) -> usize {
+        self.dir_entries.len()
+    }
+
+    /// Whether a record for a name `name_len` bytes long still fits in the block.
+    pub fn has_room_for(&self, name_len: usize) -> bool {
+        let used: usize = self.dir_entries.iter().map(Entry::record_len).sum();
+        used + dir_record_len(name_len) <= self.block_size
+    }
+
+    /// Moves the records with the higher name hashes out to a new block, so
+    /// both halves take about the same room, and returns it with the lowest hash
+    /// it holds. Records sharing a hash stay in the same block.
+    pub fn split(&mut self) -> Result<(u32, DirBlock)> {
+        self.dir_entries.sort_by_key(|e| name_hash(&e.name));
+        let hashes: Vec<u32> = self.dir_entries.iter().map(|e| name_hash(&e.name)).collect();
+        let mut used = vec![0];
+        for entry in self.dir_entries.iter() {
+            used.push(used[used.len() - 1] + entry.record_len());
+        }
+        let total = used[used.len() - 1];
+        let at = (1..hashes.len())
+            .filter(|&k| hashes[k - 1] != hashes[k])
+            .filter(|&k| used[k] <= self.block_size && total - used[k] <= self.block_size)
+            .min_by_key(|&k| used[k].abs_diff(total - used[k]));
+        match at {
+            Some(at) => {
+                let upper = Self {
+                    block_size: self.block_size,
+                    dir_entries: self.dir_entries.split_off(at),
+                };
+                Ok((hashes[at], upper))
+            },
+            None => Err(FSError::DirFullError),
         }
     }
 }
 
-#[derive(Debug, Default, Copy, Clone, Serialize, Deserialize)]
+/// Index block - a node of the hashed index of a directory. Each entry holds a
+/// child block and the lowest name hash it covers, the first entry covering
+/// hash 0. The children are leaves, plain directory blocks, when `levels` is 0,
+/// and index blocks one level down otherwise.
+///
+/// | offset | size | field                                            |
+/// |--------|------|--------------------------------------------------|
+/// | 0      | 8    | unused directory record spanning the whole block |
+/// | 8      | 2    | number of entries                                |
+/// | 10     | 1    | levels of index blocks below this one            |
+/// | 11     | 1    | reserved                                         |
+/// | 12     | 8n   | entries: lowest hash u32, child block u32        |
+///
+/// The leading record makes the block read as an empty directory block, so
+/// the entries of an indexed directory can still be found block by block.
+#[derive(Debug)]
+pub struct IndexBlock {
+    block_size: usize,
+    levels: u8,
+    entries: Vec<(u32, u32)>,
+}
+
+impl BlockBinary for IndexBlock {
+    fn to_bytes(&self) -> Result<Vec<u8>> {
+        let mut bytes = vec![0u8; self.block_size];
+        Entry::default().write(&mut bytes, 0, self.block_size);
+        put_u16(&mut bytes, 8, self.entries.len() as u16);
+        bytes[10] = self.levels;
+        for (i, &(hash, child)) in self.entries.iter().enumerate() {
+            let offset = INDEX_HEADER_SIZE + i * INDEX_ENTRY_SIZE;
+            put_u32(&mut bytes, offset, hash);
+            put_u32(&mut bytes, offset + 4, child);
+        }
+        Ok(bytes)
+    }
+
+    fn from_bytes(block_data: &[u8]) -> Result<Self> {
+        check_len(block_data, INDEX_HEADER_SIZE)?;
+        let count = get_u16(block_data, 8) as usize;
+        if count > index_entries_per_block(block_data.len()) {
+            return Err(FSError::FileSysError);
+        }
+        let entries = (0..count)
+            .map(|i| INDEX_HEADER_SIZE + i * INDEX_ENTRY_SIZE)
+            .map(|offset| (get_u32(block_data, offset), get_u32(block_data, offset + 4)))
+            .collect();
+        Ok(Self { block_size: block_data.len(), levels: block_data[10], entries })
+    }
+}
+
+impl IndexBlock {
+    pub fn new(block_size: usize, levels: u8, entries: Vec<(u32, u32)>) -> Self {
+        Self { block_size, levels, entries }
+    }
+
+    /// Child block covering `hash`.
+    pub fn child(&self, hash: u32) -> Result<usize> {
+        match self.entries.partition_point(|&(lowest, _)| lowest <= hash) {
+            0 => Err(FSError::FileSysError),
+            pos => Ok(self.entries[pos - 1].1 as usize),
+        }
+    }
+
+    /// Adds `child`, covering hashes from `hash` on.
+    pub fn insert(&mut self, hash: u32, child: usize) {
+        let pos = self.entries.partition_point(|&(lowest, _)| lowest < hash);
+        self.entries.insert(pos, (hash, child as u32));
+    }
+
+    /// Drops the child covering `hash`; its hashes fall to the child before it,
+    /// or to the one after it when it was the first.
+    pub fn remove(&mut self, hash: u32) {
+        let pos = self.entries.partition_point(|&(lowest, _)| lowest <= hash);
+        if pos == 0 {
+            return;
+        }
+        let (lowest, _) = self.entries.remove(pos - 1);
+        if pos == 1 {
+            if let Some(first) = self.entries.first_mut() {
+                first.0 = lowest;
+            }
+        }
+    }
+
+    /// Moves the upper half of the entries out to a new index block at the same level.
+    pub fn split(&mut self) -> Self {
+        let upper = self.entries.split_off(self.entries.len() / 2);
+        Self { block_size: self.block_size, levels: self.levels, entries: upper }
+    }
+}
+
+/// Directory record as stored in a directory block
+///
+/// | offset | size | field                                  |
+/// |--------|------|----------------------------------------|
+/// | 0      | 4    | inode number, 0 if unused              |
+/// | 4      | 2    | record length, 0 standing for 65536    |
+/// | 6      | 1    | name length                            |
+/// | 7      | 1    | file type, the `InodeType` tag         |
+/// | 8      | n    | name, UTF-8 without a terminating NUL  |
+#[derive(Debug, Default, Clone)]
 pub struct Entry {
-    name: [u8; MAX_FNAME_SIZE],
-    block_num: usize,
+    ino: u32,
+    file_type: u8,
+    name: String,
+}
+
+impl Entry {
+    fn record_len(&self) -> usize {
+        dir_record_len(self.name.len())
+    }
+
+    /// Reads the record at `offset`, returning it with its length.
+    fn read(data: &[u8], offset: usize) -> Result<(Self, usize)> {
+        check_len(data, offset + DIR_RECORD_HEADER_SIZE)?;
+        let rec_len = match get_u16(data, offset + 4) {
+            0 => 1 << 16,
+            rec_len => rec_len as usize,
+        };
+        let name_len = data[offset + 6] as usize;
+        if rec_len < dir_record_len(name_len) || offset + rec_len > data.len() {
+            return Err(FSError::FileSysError);
+        }
+        let name_start = offset + DIR_RECORD_HEADER_SIZE;
+        let name = std::str::from_utf8(&data[name_start..name_start + name_len])
+            .map_err(|_| FSError::FileSysError)?;
+        let entry = Self {
+            ino: get_u32(data, offset),
+            file_type: data[offset + 7],
+            name: name.to_owned(),
+        };
+        Ok((entry, rec_len))
+    }
+
+    fn write(&self, data: &mut [u8], offset: usize, rec_len: usize) {
+        put_u32(data, offset, self.ino);
+        put_u16(data, offset + 4, rec_len as u16);
+        data[offset + 6] = self.name.len() as u8;
+        data[offset + 7] = self.file_type;
+        let name_start = offset + DIR_RECORD_HEADER_SIZE;
+        data[name_start..name_start + self.name.len()].copy_from_slice(self.name.as_bytes());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::virtual_file_sys::disk::MemDisk;
+    use crate::virtual_file_sys::file_sys::BasicFileSys;
+    use super::*;
+
+    #[test]
+    fn test_format_time() {
+        assert_eq!(format_time(0), "1970-01-01 00:00:00");
+        assert_eq!(format_time(951782400), "2000-02-29 00:00:00");
+        assert_eq!(format_time(4102444859), "2100-01-01 00:00:59");
+    }
+
+    #[test]
+    fn test_block_mapping() -> Result<()> {
+        let disk = MemDisk::new(512, 1024);
+        let wrapped_file_sys = WrappedFileSys::new(BasicFileSys::format_device(Box::new(disk), "")?);
+        let free_blocks = wrapped_file_sys.file_sys().count_free_blocks()?;
+
+        let mut file = FileInode::new(&wrapped_file_sys)?;
+        file.clear_inline();
+        // Every other block keeps each block in an extent of its own, so the 400
+        // extents spill into the indirect and double-indirect extent blocks.
+        let mut data_blocks = vec![];
+        let mut gaps = vec![];
+        for _ in 0..400 {
+            let block_id = wrapped_file_sys.file_sys().get_free_block()?.unwrap();
+            data_blocks.push(block_id);
+            file.map.add_extents(&wrapped_file_sys, &[(block_id, 1)])?;
+            gaps.push(wrapped_file_sys.file_sys().get_free_block()?.unwrap());
+        }
+        for gap in gaps {
+            wrapped_file_sys.file_sys().reclaim_block(gap)?;
+        }
+        let file = FileInode::retrieve(&wrapped_file_sys, file.get_id())?;
+        assert_eq!(file.map.extents.len(), 400);
+        assert_eq!((0..file.map.num_blocks()).map(|i| file.block_id(i).unwrap()).collect::<Vec<_>>(), data_blocks);
+
+        // Removing blocks hands the extent blocks that are no longer needed back.
+        let mut file = file;
+        for &block_id in data_blocks.iter().rev() {
+            file.map.remove_block(&wrapped_file_sys, block_id)?;
+            wrapped_file_sys.file_sys().reclaim_block(block_id)?;
+        }
+        let file = FileInode::retrieve(&wrapped_file_sys, file.get_id())?;
+        assert_eq!(file.map.num_blocks(), 0);
+        file.destroy(&wrapped_file_sys)?;
+        assert_eq!(wrapped_file_sys.file_sys().count_free_blocks()?, free_blocks);
+        Ok(())
+    }
+
+    #[test]
+    fn test_extents() -> Result<()> {
+        let disk = MemDisk::new(512, 1024);
+        let wrapped_file_sys = WrappedFileSys::new(BasicFileSys::format_device(Box::new(disk), "")?);
+        let mut file = FileInode::new(&wrapped_file_sys)?;
+        file.clear_inline();
+
+        // Consecutive blocks merge into a single extent.
+        let (start, len) = wrapped_file_sys.file_sys().allocate_extent(None, 10)?.unwrap();
+        assert_eq!(len, 10);
+        file.map.add_extents(&wrapped_file_sys, &[(start, 6), (start + 6, 4)])?;
+        assert_eq!(file.map.extents, vec![Extent { logical: 0, start: start as u32, len: 10 }]);
+        assert_eq!(file.allocation_goal(), Some(start + 10));
+
+        // Dropping a block from the middle splits the extent.
+        file.map.remove_block(&wrapped_file_sys, start + 3)?;
+        let file = FileInode::retrieve(&wrapped_file_sys, file.get_id())?;
+        assert_eq!(file.map.extents, vec![
+            Extent { logical: 0, start: start as u32, len: 3 },
+            Extent { logical: 3, start: start as u32 + 4, len: 6 },
+        ]);
+        assert_eq!(file.block_id(3), Some(start + 4));
+        assert_eq!(file.block_id(9), None);
+
+        // Filling a hole joins the extents on both sides of it.
+        let mut file = file;
+        file.map.map_extents(&wrapped_file_sys, &[(9, start + 10, 1)])?;
+        file.map.map_extents(&wrapped_file_sys, &[(12, start + 13, 2)])?;
+        assert_eq!(file.map.next_mapped(10), Some(12));
+        file.map.map_extents(&wrapped_file_sys, &[(10, start + 11, 2)])?;
+        assert_eq!(file.map.extents, vec![
+            Extent { logical: 0, start: start as u32, len: 3 },
+            Extent { logical: 3, start: start as u32 + 4, len: 11 },
+        ]);
+        assert!(file.map.map_extents(&wrapped_file_sys, &[(13, start + 20, 1)]).is_err());
+        Ok(())
+    }
 }
@@ -1,6 +1,7 @@
 mod inode;
 pub mod file_sys;
 mod block;
-mod disk;
+pub mod disk;
+mod super_block;
 
-pub use block::{MAX_FNAME_SIZE};
+pub use super_block::FormatOptions;
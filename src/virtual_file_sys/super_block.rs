@@ -0,0 +1,375 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::time::{SystemTime, UNIX_EPOCH};
+use crate::error::{FSError, Result};
+use crate::virtual_file_sys::block::*;
+
+pub const SUPER_BLOCK_MAGIC: u32 = 0x5352_5249;
+pub const FORMAT_VERSION: u32 = 11;
+
+pub const SUPER_BLOCK_ID: usize = 0;
+pub const BITMAP_START: usize = 1;
+
+pub const MAX_LABEL_SIZE: usize = 16;
+
+/// Inode number of the root directory; inode 0 marks an unused entry.
+pub const ROOT_INO: usize = 1;
+
+/// Features an older implementation can safely ignore.
+pub const SUPPORTED_FEATURE_COMPAT: u32 = 0;
+/// Small files keep their contents in the inode.
+pub const FEATURE_INCOMPAT_INLINE_DATA: u32 = 0x0001;
+
+/// Features that change the on-disk layout; images using unknown ones are refused.
+pub const SUPPORTED_FEATURE_INCOMPAT: u32 = FEATURE_INCOMPAT_INLINE_DATA;
+/// Large directories keep a hashed index of their entries. Their blocks still
+/// read as ordinary directory blocks, but adding entries has to keep the index up to date.
+pub const FEATURE_RO_COMPAT_DIR_INDEX: u32 = 0x0001;
+/// Features that only matter when writing; images using unknown ones are refused
+/// since the file system is always mounted read-write.
+pub const SUPPORTED_FEATURE_RO_COMPAT: u32 = FEATURE_RO_COMPAT_DIR_INDEX;
+
+/// Geometry and identity of a file system, chosen when the disk image is formatted
+#[derive(Debug, Clone)]
+pub struct FormatOptions {
+    pub block_size: usize,
+    pub num_blocks: usize,
+    pub label: String,
+    /// Size of the inode table; by default one inode per four blocks.
+    pub num_inodes: Option<usize>,
+    /// Whether directories that outgrow one block get a hashed index.
+    pub dir_index: bool,
+}
+
+impl Default for FormatOptions {
+    fn default() -> Self {
+        Self {
+            block_size: DEFAULT_BLOCK_SIZE,
+            num_blocks: DEFAULT_NUM_BLOCKS,
+            label: String::new(),
+            num_inodes: None,
+            dir_index: true,
+        }
+    }
+}
+
+impl FormatOptions {
+    pub fn num_inodes(&self) -> usize {
+        self.num_inodes.unwrap_or((self.num_blocks / 4).max(16))
+    }
+}
+
+/// Number of blocks needed by a free-block bitmap covering `num_blocks` blocks.
+pub fn bitmap_blocks(block_size: usize, num_blocks: usize) -> usize {
+    num_blocks.div_ceil(block_size * 8)
+}
+
+/// Number of blocks needed by an inode table of `num_inodes` inodes.
+pub fn inode_table_blocks(block_size: usize, num_inodes: usize) -> usize {
+    (num_inodes * INODE_SIZE).div_ceil(block_size)
+}
+
+/// Super block - describes the geometry and format of a disk image.
+/// Stored at the start of block 0, every field little-endian:
+///
+/// | offset | size | field               |
+/// |--------|------|---------------------|
+/// | 0      | 4    | magic               |
+/// | 4      | 4    | version             |
+/// | 8      | 4    | block_size          |
+/// | 12     | 4    | num_blocks          |
+/// | 16     | 4    | bitmap_start        |
+/// | 20     | 4    | bitmap_blocks       |
+/// | 24     | 4    | root_inode          |
+/// | 28     | 4    | feature_compat      |
+/// | 32     | 4    | feature_incompat    |
+/// | 36     | 4    | feature_ro_compat   |
+/// | 40     | 16   | uuid                |
+/// | 56     | 16   | label               |
+/// | 72     | 8    | created             |
+/// | 80     | 8    | mounted             |
+/// | 88     | 4    | inode_bitmap_start  |
+/// | 92     | 4    | inode_bitmap_blocks |
+/// | 96     | 4    | inode_table_start   |
+/// | 100    | 4    | inode_table_blocks  |
+/// | 104    | 4    | num_inodes          |
+/// | 108    | 4    | inode_size          |
+///
+/// The block bitmap, the inode bitmap and the inode table follow the super
+/// block in that order; data blocks start right after the inode table.
+#[derive(Debug)]
+pub struct SuperBlock {
+    magic: u32,
+    version: u32,
+    block_size: u32,
+    num_blocks: u32,
+    bitmap_start: u32,
+    bitmap_blocks: u32,
+    root_inode: u32,
+    uuid: [u8; 16],
+    label: [u8; MAX_LABEL_SIZE],
+    created: u64,
+    mounted: u64,
+    feature_compat: u32,
+    feature_incompat: u32,
+    feature_ro_compat: u32,
+    inode_bitmap_start: u32,
+    inode_bitmap_blocks: u32,
+    inode_table_start: u32,
+    inode_table_blocks: u32,
+    num_inodes: u32,
+    inode_size: u32,
+}
+
+impl SuperBlock {
+    pub fn new(options: &FormatOptions) -> Result<Self> {
+        let FormatOptions { block_size, num_blocks, label, dir_index, .. } = options;
+        let num_inodes = options.num_inodes();
+        if label.len() > MAX_LABEL_SIZE {
+            return Err(FSError::LabelTooLongError);
+        }
+        if !block_size.is_power_of_two() || !(MIN_BLOCK_SIZE..=MAX_BLOCK_SIZE).contains(block_size) {
+            return Err(FSError::InvalidGeometryError(format!("block size {block_size}")));
+        }
+        // Inode 0 is never used, so the root directory needs a table of at least two.
+        if num_inodes < ROOT_INO + 1 || num_inodes > u32::MAX as usize {
+            return Err(FSError::InvalidGeometryError(format!("{num_inodes} inodes")));
+        }
+        let bitmap_blocks = bitmap_blocks(*block_size, *num_blocks);
+        let inode_bitmap_start = BITMAP_START + bitmap_blocks;
+        let inode_bitmap_blocks = self::bitmap_blocks(*block_size, num_inodes);
+        let inode_table_start = inode_bitmap_start + inode_bitmap_blocks;
+        let inode_table_blocks = inode_table_blocks(*block_size, num_inodes);
+        // Room for the metadata, the root directory and at least one data block.
+        if *num_blocks < inode_table_start + inode_table_blocks + 2 || *num_blocks > u32::MAX as usize {
+            return Err(FSError::InvalidGeometryError(format!("{num_blocks} blocks")));
+        }
+        let mut label_bytes = [0u8; MAX_LABEL_SIZE];
+        label_bytes[..label.len()].copy_from_slice(label.as_bytes());
+        Ok(Self {
+            magic: SUPER_BLOCK_MAGIC,
+            version: FORMAT_VERSION,
+            block_size: *block_size as u32,
+            num_blocks: *num_blocks as u32,
+            bitmap_start: BITMAP_START as u32,
+            bitmap_blocks: bitmap_blocks as u32,
+            root_inode: ROOT_INO as u32,
+            uuid: generate_uuid(),
+            label: label_bytes,
+            created: now(),
+            mounted: 0,
+            feature_compat: SUPPORTED_FEATURE_COMPAT,
+            feature_incompat: SUPPORTED_FEATURE_INCOMPAT,
+            feature_ro_compat: match dir_index {
+                true => FEATURE_RO_COMPAT_DIR_INDEX,
+                false => 0,
+            },
+            inode_bitmap_start: inode_bitmap_start as u32,
+            inode_bitmap_blocks: inode_bitmap_blocks as u32,
+            inode_table_start: inode_table_start as u32,
+            inode_table_blocks: inode_table_blocks as u32,
+            num_inodes: num_inodes as u32,
+            inode_size: INODE_SIZE as u32,
+        })
+    }
+
+    /// Checks that the disk was formatted with a layout this version understands
+    /// and that the disk holding it, `disk_size` bytes long, is large enough.
+    pub fn validate(&self, disk_size: u64) -> Result<()> {
+        if self.magic != SUPER_BLOCK_MAGIC {
+            return Err(FSError::NotFormattedError);
+        }
+        if self.version != FORMAT_VERSION {
+            return Err(FSError::UnsupportedFormatError(format!("version {}", self.version)));
+        }
+        let block_size = self.block_size as usize;
+        if !block_size.is_power_of_two() || !(MIN_BLOCK_SIZE..=MAX_BLOCK_SIZE).contains(&block_size) {
+            return Err(FSError::UnsupportedFormatError(format!("block size {}", self.block_size)));
+        }
+        if self.num_blocks as u64 * self.block_size as u64 > disk_size {
+            return Err(FSError::UnsupportedFormatError(
+                format!("{} blocks on a disk of {} bytes", self.num_blocks, disk_size)));
+        }
+        if self.bitmap_start as usize != BITMAP_START
+            || self.bitmap_blocks as usize != bitmap_blocks(block_size, self.num_blocks as usize) {
+            return Err(FSError::UnsupportedFormatError(
+                format!("bitmap at block {} spanning {} blocks", self.bitmap_start, self.bitmap_blocks)));
+        }
+        let num_inodes = self.num_inodes as usize;
+        if self.inode_size as usize != INODE_SIZE
+            || num_inodes <= ROOT_INO
+            || self.root_inode as usize != ROOT_INO
+            || self.inode_bitmap_start as usize != BITMAP_START + self.bitmap_blocks as usize
+            || self.inode_bitmap_blocks as usize != bitmap_blocks(block_size, num_inodes)
+            || self.inode_table_start != self.inode_bitmap_start + self.inode_bitmap_blocks
+            || self.inode_table_blocks as usize != inode_table_blocks(block_size, num_inodes)
+            || self.get_first_data_block() >= self.num_blocks as usize {
+            return Err(FSError::UnsupportedFormatError(
+                format!("{} inodes of {} bytes at block {}", self.num_inodes, self.inode_size, self.inode_table_start)));
+        }
+        let incompat = self.feature_incompat & !SUPPORTED_FEATURE_INCOMPAT;
+        if incompat != 0 {
+            return Err(FSError::UnsupportedFormatError(format!("incompatible features {incompat:#x}")));
+        }
+        let ro_compat = self.feature_ro_compat & !SUPPORTED_FEATURE_RO_COMPAT;
+        if ro_compat != 0 {
+            return Err(FSError::UnsupportedFormatError(format!("read-only features {ro_compat:#x}")));
+        }
+        Ok(())
+    }
+
+    /// Records the time of the current mount.
+    pub fn touch_mounted(&mut self) {
+        self.mounted = now();
+    }
+
+    pub fn get_block_size(&self) -> usize {
+        self.block_size as usize
+    }
+
+    pub fn get_num_blocks(&self) -> usize {
+        self.num_blocks as usize
+    }
+
+    pub fn get_bitmap_start(&self) -> usize {
+        self.bitmap_start as usize
+    }
+
+    pub fn get_bitmap_blocks(&self) -> usize {
+        self.bitmap_blocks as usize
+    }
+
+    pub fn get_root_inode(&self) -> usize {
+        self.root_inode as usize
+    }
+
+    pub fn get_inode_bitmap_start(&self) -> usize {
+        self.inode_bitmap_start as usize
+    }
+
+    pub fn get_inode_bitmap_blocks(&self) -> usize {
+        self.inode_bitmap_blocks as usize
+    }
+
+    pub fn get_inode_table_start(&self) -> usize {
+        self.inode_table_start as usize
+    }
+
+    pub fn get_inode_table_blocks(&self) -> usize {
+        self.inode_table_blocks as usize
+    }
+
+    pub fn get_num_inodes(&self) -> usize {
+        self.num_inodes as usize
+    }
+
+    /// First block past the metadata, where data blocks start.
+    pub fn get_first_data_block(&self) -> usize {
+        self.get_inode_table_start() + self.get_inode_table_blocks()
+    }
+
+    pub fn get_uuid(&self) -> [u8; 16] {
+        self.uuid
+    }
+
+    pub fn get_label(&self) -> String {
+        let len = self.label.iter().position(|&b| b == 0).unwrap_or(MAX_LABEL_SIZE);
+        String::from_utf8_lossy(&self.label[..len]).into_owned()
+    }
+
+    pub fn get_created(&self) -> u64 {
+        self.created
+    }
+
+    pub fn get_mounted(&self) -> u64 {
+        self.mounted
+    }
+
+    pub fn has_feature_incompat(&self, feature: u32) -> bool {
+        self.feature_incompat & feature != 0
+    }
+
+    pub fn has_feature_ro_compat(&self, feature: u32) -> bool {
+        self.feature_ro_compat & feature != 0
+    }
+}
+
+/// Bytes taken by the super block on disk.
+pub const SUPER_BLOCK_SIZE: usize = 112;
+
+impl BlockBinary for SuperBlock {
+    fn to_bytes(&self) -> Result<Vec<u8>> {
+        let mut bytes = vec![0u8; SUPER_BLOCK_SIZE];
+        put_u32(&mut bytes, 0, self.magic);
+        put_u32(&mut bytes, 4, self.version);
+        put_u32(&mut bytes, 8, self.block_size);
+        put_u32(&mut bytes, 12, self.num_blocks);
+        put_u32(&mut bytes, 16, self.bitmap_start);
+        put_u32(&mut bytes, 20, self.bitmap_blocks);
+        put_u32(&mut bytes, 24, self.root_inode);
+        put_u32(&mut bytes, 28, self.feature_compat);
+        put_u32(&mut bytes, 32, self.feature_incompat);
+        put_u32(&mut bytes, 36, self.feature_ro_compat);
+        bytes[40..56].copy_from_slice(&self.uuid);
+        bytes[56..72].copy_from_slice(&self.label);
+        put_u64(&mut bytes, 72, self.created);
+        put_u64(&mut bytes, 80, self.mounted);
+        put_u32(&mut bytes, 88, self.inode_bitmap_start);
+        put_u32(&mut bytes, 92, self.inode_bitmap_blocks);
+        put_u32(&mut bytes, 96, self.inode_table_start);
+        put_u32(&mut bytes, 100, self.inode_table_blocks);
+        put_u32(&mut bytes, 104, self.num_inodes);
+        put_u32(&mut bytes, 108, self.inode_size);
+        Ok(bytes)
+    }
+
+    fn from_bytes(block_data: &[u8]) -> Result<Self> {
+        check_len(block_data, SUPER_BLOCK_SIZE)?;
+        let mut uuid = [0u8; 16];
+        uuid.copy_from_slice(&block_data[40..56]);
+        let mut label = [0u8; MAX_LABEL_SIZE];
+        label.copy_from_slice(&block_data[56..72]);
+        Ok(Self {
+            magic: get_u32(block_data, 0),
+            version: get_u32(block_data, 4),
+            block_size: get_u32(block_data, 8),
+            num_blocks: get_u32(block_data, 12),
+            bitmap_start: get_u32(block_data, 16),
+            bitmap_blocks: get_u32(block_data, 20),
+            root_inode: get_u32(block_data, 24),
+            feature_compat: get_u32(block_data, 28),
+            feature_incompat: get_u32(block_data, 32),
+            feature_ro_compat: get_u32(block_data, 36),
+            uuid,
+            label,
+            created: get_u64(block_data, 72),
+            mounted: get_u64(block_data, 80),
+            inode_bitmap_start: get_u32(block_data, 88),
+            inode_bitmap_blocks: get_u32(block_data, 92),
+            inode_table_start: get_u32(block_data, 96),
+            inode_table_blocks: get_u32(block_data, 100),
+            num_inodes: get_u32(block_data, 104),
+            inode_size: get_u32(block_data, 108),
+        })
+    }
+}
+
+/// Seconds since the Unix epoch.
+pub fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+/// Random (version 4) UUID, seeded from the randomly keyed std hasher.
+fn generate_uuid() -> [u8; 16] {
+    let mut uuid = [0u8; 16];
+    for (i, half) in uuid.chunks_mut(8).enumerate() {
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_u128(SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_nanos()).unwrap_or(0));
+        hasher.write_u32(std::process::id());
+        hasher.write_usize(i);
+        half.copy_from_slice(&hasher.finish().to_le_bytes());
+    }
+    uuid[6] = (uuid[6] & 0x0F) | 0x40;
+    uuid[8] = (uuid[8] & 0x3F) | 0x80;
+    uuid
+}